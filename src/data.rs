@@ -1,3 +1,10 @@
 #![allow(dead_code)]
+pub mod augmentation;
+pub mod cifar;
+pub mod dataset;
+pub mod definition;
 pub mod generator;
 pub mod idx_parser;
+pub mod labels;
+pub mod loader;
+pub mod preprocessing;
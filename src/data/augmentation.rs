@@ -0,0 +1,417 @@
+use crate::error::{Error, Result};
+use crate::math::matrix::Matrix;
+use crate::math::random::standard_normal;
+use rand::Rng;
+
+pub const MNIST_SIDE: usize = 28;
+
+// A random change applied to an image with `probability`. Images are grids in [0, 1] with a black
+// (0) background, which is also what pixels moved in from outside the grid get.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Augmentation {
+    // Shift by up to `pixels` horizontally and vertically
+    Translate {
+        probability: f64,
+        pixels: f64,
+    },
+    // Rotate around the centre by up to `degrees` either way
+    Rotate {
+        probability: f64,
+        degrees: f64,
+    },
+    // Scale around the centre by a factor within [1 - range, 1 + range]
+    Zoom {
+        probability: f64,
+        range: f64,
+    },
+    // Displace every pixel along a random field smoothed with a Gaussian of `sigma` pixels and scaled
+    // by `alpha`, as in Simard et al. 2003
+    Elastic {
+        probability: f64,
+        alpha: f64,
+        sigma: f64,
+    },
+    // Add Gaussian noise of standard deviation `std` to every pixel
+    Noise {
+        probability: f64,
+        std: f64,
+    },
+    // Black out a random rectangle covering up to `max_area` of the image
+    Erase {
+        probability: f64,
+        max_area: f64,
+    },
+}
+
+impl Augmentation {
+    fn probability(&self) -> f64 {
+        match *self {
+            Augmentation::Translate { probability, .. }
+            | Augmentation::Rotate { probability, .. }
+            | Augmentation::Zoom { probability, .. }
+            | Augmentation::Elastic { probability, .. }
+            | Augmentation::Noise { probability, .. }
+            | Augmentation::Erase { probability, .. } => probability,
+        }
+    }
+}
+
+// Applies augmentations, in order, to (height * width) images flattened row by row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Augmenter {
+    height: usize,
+    width: usize,
+    augmentations: Vec<Augmentation>,
+}
+
+impl Augmenter {
+    pub fn new(height: usize, width: usize, augmentations: Vec<Augmentation>) -> Result<Self> {
+        for a in &augmentations {
+            if !(0.0..=1.0).contains(&a.probability()) {
+                return Err(Error::InvalidArgument(format!(
+                    "Probability of {:?} must be within [0, 1]",
+                    a
+                )));
+            }
+            let strength_ok = match *a {
+                Augmentation::Translate { pixels, .. } => pixels >= 0.0,
+                Augmentation::Rotate { degrees, .. } => degrees >= 0.0,
+                Augmentation::Zoom { range, .. } => (0.0..1.0).contains(&range),
+                Augmentation::Elastic { alpha, sigma, .. } => alpha >= 0.0 && sigma > 0.0,
+                Augmentation::Noise { std, .. } => std >= 0.0,
+                Augmentation::Erase { max_area, .. } => (0.0..=1.0).contains(&max_area),
+            };
+            if !strength_ok {
+                return Err(Error::InvalidArgument(format!(
+                    "Strength of {:?} is out of range",
+                    a
+                )));
+            }
+        }
+        Ok(Augmenter {
+            height,
+            width,
+            augmentations,
+        })
+    }
+
+    // For 28x28 MNIST style images
+    pub fn mnist(augmentations: Vec<Augmentation>) -> Result<Self> {
+        Augmenter::new(MNIST_SIDE, MNIST_SIDE, augmentations)
+    }
+
+    pub fn image_size(&self) -> usize {
+        self.height * self.width
+    }
+
+    // Augments every column of a (pixels x n) batch independently
+    pub fn apply_batch(&self, x: &mut Matrix, rng: &mut impl Rng) -> Result<()> {
+        if x.rows != self.image_size() {
+            return Err(Error::Shape(format!(
+                "Images have {} pixels but the augmenter works on {}x{}",
+                x.rows, self.height, self.width
+            )));
+        }
+        // Pixels are read through `get` so a transposed batch works too, the result is row-major
+        let mut data = vec![0.0; x.rows * x.cols];
+        let mut image = vec![0.0; x.rows];
+        for j in 0..x.cols {
+            for (p, v) in image.iter_mut().enumerate() {
+                *v = x.get(p, j);
+            }
+            self.apply(&mut image, rng);
+            for (p, v) in image.iter().enumerate() {
+                data[p * x.cols + j] = *v;
+            }
+        }
+        *x = Matrix::new(x.rows, x.cols, data);
+        Ok(())
+    }
+
+    // Augments one image in place. `image` must hold `image_size()` pixels.
+    pub fn apply(&self, image: &mut [f64], rng: &mut impl Rng) {
+        for a in &self.augmentations {
+            if rng.random::<f64>() >= a.probability() {
+                continue;
+            }
+            match *a {
+                Augmentation::Translate { pixels, .. } => {
+                    let dy = rng.random_range(-1.0..=1.0) * pixels;
+                    let dx = rng.random_range(-1.0..=1.0) * pixels;
+                    self.warp(image, |y, x| (y - dy, x - dx));
+                }
+                Augmentation::Rotate { degrees, .. } => {
+                    let angle = rng.random_range(-1.0..=1.0) * degrees.to_radians();
+                    let (sin, cos) = angle.sin_cos();
+                    let (cy, cx) = self.centre();
+                    // Pull every output pixel from its position rotated back by `angle`
+                    self.warp(image, |y, x| {
+                        let (y, x) = (y - cy, x - cx);
+                        (cy + cos * y - sin * x, cx + sin * y + cos * x)
+                    });
+                }
+                Augmentation::Zoom { range, .. } => {
+                    let scale = 1.0 + rng.random_range(-1.0..=1.0) * range;
+                    let (cy, cx) = self.centre();
+                    self.warp(image, |y, x| (cy + (y - cy) / scale, cx + (x - cx) / scale));
+                }
+                Augmentation::Elastic { alpha, sigma, .. } => {
+                    let field = |rng: &mut _| {
+                        let noise: Vec<f64> = (0..self.image_size())
+                            .map(|_| Rng::random_range(rng, -1.0..=1.0))
+                            .collect();
+                        self.smooth(&noise, sigma)
+                    };
+                    let dy = field(rng);
+                    let dx = field(rng);
+                    let w = self.width;
+                    self.warp(image, |y, x| {
+                        let p = y as usize * w + x as usize;
+                        (y + alpha * dy[p], x + alpha * dx[p])
+                    });
+                }
+                Augmentation::Noise { std, .. } => {
+                    for v in image.iter_mut() {
+                        *v += std * standard_normal(rng);
+                    }
+                }
+                Augmentation::Erase { max_area, .. } => {
+                    let area = rng.random_range(0.0..=max_area) * self.image_size() as f64;
+                    let aspect: f64 = rng.random_range(0.3..=3.3);
+                    let h = ((area * aspect).sqrt().round() as usize).min(self.height);
+                    let w = ((area / aspect).sqrt().round() as usize).min(self.width);
+                    let top = rng.random_range(0..=self.height - h);
+                    let left = rng.random_range(0..=self.width - w);
+                    for y in top..top + h {
+                        image[y * self.width + left..y * self.width + left + w].fill(0.0);
+                    }
+                }
+            }
+        }
+    }
+
+    fn centre(&self) -> (f64, f64) {
+        (
+            (self.height as f64 - 1.0) / 2.0,
+            (self.width as f64 - 1.0) / 2.0,
+        )
+    }
+
+    // Replaces every pixel with the bilinear sample of the original at `source(y, x)`
+    fn warp(&self, image: &mut [f64], source: impl Fn(f64, f64) -> (f64, f64)) {
+        let original = image.to_vec();
+        let pixel = |y: isize, x: isize| {
+            if (0..self.height as isize).contains(&y) && (0..self.width as isize).contains(&x) {
+                original[y as usize * self.width + x as usize]
+            } else {
+                0.0
+            }
+        };
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (sy, sx) = source(y as f64, x as f64);
+                let (y0, x0) = (sy.floor(), sx.floor());
+                let (fy, fx) = (sy - y0, sx - x0);
+                let (y0, x0) = (y0 as isize, x0 as isize);
+                image[y * self.width + x] = (1.0 - fy)
+                    * ((1.0 - fx) * pixel(y0, x0) + fx * pixel(y0, x0 + 1))
+                    + fy * ((1.0 - fx) * pixel(y0 + 1, x0) + fx * pixel(y0 + 1, x0 + 1));
+            }
+        }
+    }
+
+    // Separable Gaussian blur, zero beyond the borders
+    fn smooth(&self, values: &[f64], sigma: f64) -> Vec<f64> {
+        let radius = (3.0 * sigma).ceil() as isize;
+        let kernel: Vec<f64> = (-radius..=radius)
+            .map(|k| (-(k * k) as f64 / (2.0 * sigma * sigma)).exp())
+            .collect();
+        let total: f64 = kernel.iter().sum();
+        let (h, w) = (self.height as isize, self.width as isize);
+
+        let blur = |values: &[f64], step: (isize, isize)| -> Vec<f64> {
+            let mut out = vec![0.0; values.len()];
+            for y in 0..h {
+                for x in 0..w {
+                    out[(y * w + x) as usize] = (-radius..=radius)
+                        .zip(&kernel)
+                        .map(|(k, weight)| {
+                            let (sy, sx) = (y + k * step.0, x + k * step.1);
+                            if (0..h).contains(&sy) && (0..w).contains(&sx) {
+                                weight * values[(sy * w + sx) as usize]
+                            } else {
+                                0.0
+                            }
+                        })
+                        .sum::<f64>()
+                        / total;
+                }
+            }
+            out
+        };
+        blur(&blur(values, (0, 1)), (1, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::random::SeededRng;
+
+    // 5x5 image with a single lit pixel at (row, col)
+    fn dot(row: usize, col: usize) -> Vec<f64> {
+        let mut image = vec![0.0; 25];
+        image[row * 5 + col] = 1.0;
+        image
+    }
+
+    fn always(augmentation: Augmentation) -> Augmenter {
+        Augmenter::new(5, 5, vec![augmentation]).unwrap()
+    }
+
+    #[test]
+    fn test_translate() {
+        let augmenter = always(Augmentation::Translate {
+            probability: 1.0,
+            pixels: 2.0,
+        });
+        let mut image = dot(2, 2);
+        augmenter.apply(&mut image, &mut SeededRng::new(1));
+
+        // Moved by a fractional offset, the mass spreads but is kept while inside the grid
+        assert!((image.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!(image[12] < 1.0);
+    }
+
+    #[test]
+    fn test_rotation_and_zoom_keep_the_centre() {
+        for augmentation in [
+            Augmentation::Rotate {
+                probability: 1.0,
+                degrees: 30.0,
+            },
+            Augmentation::Zoom {
+                probability: 1.0,
+                range: 0.2,
+            },
+        ] {
+            let mut image = dot(2, 2);
+            always(augmentation).apply(&mut image, &mut SeededRng::new(3));
+            assert!(image[12] > 0.5);
+        }
+
+        // A quarter turn moves the pixel right of the centre above or below it
+        let mut image = dot(2, 3);
+        let augmenter = always(Augmentation::Rotate {
+            probability: 1.0,
+            degrees: 90.0,
+        });
+        augmenter.warp(&mut image, |y, x| (2.0 + (x - 2.0), 2.0 - (y - 2.0)));
+        assert_eq!(image[7], 1.0);
+    }
+
+    #[test]
+    fn test_elastic_is_smooth_and_small() {
+        let augmenter = Augmenter::mnist(vec![Augmentation::Elastic {
+            probability: 1.0,
+            alpha: 8.0,
+            sigma: 4.0,
+        }])
+        .unwrap();
+        let mut image: Vec<f64> = (0..784).map(|p| ((p / 28) % 2) as f64).collect();
+        let original = image.clone();
+        augmenter.apply(&mut image, &mut SeededRng::new(5));
+
+        assert_ne!(image, original);
+        assert!(image.iter().all(|v| (-1e-9..=1.0 + 1e-9).contains(v)));
+    }
+
+    #[test]
+    fn test_noise_and_erase() {
+        let noise = always(Augmentation::Noise {
+            probability: 1.0,
+            std: 0.1,
+        });
+        let mut image = vec![0.5; 25];
+        noise.apply(&mut image, &mut SeededRng::new(2));
+        assert!(image.iter().all(|&v| v != 0.5 && (v - 0.5).abs() < 0.6));
+
+        let erase = always(Augmentation::Erase {
+            probability: 1.0,
+            max_area: 0.5,
+        });
+        let mut image = vec![1.0; 25];
+        erase.apply(&mut image, &mut SeededRng::new(4));
+        let erased = image.iter().filter(|&&v| v == 0.0).count();
+        assert!(erased <= 13);
+    }
+
+    #[test]
+    fn test_probability() {
+        let never = always(Augmentation::Noise {
+            probability: 0.0,
+            std: 1.0,
+        });
+        let mut image = dot(1, 1);
+        never.apply(&mut image, &mut SeededRng::new(0));
+        assert_eq!(image, dot(1, 1));
+
+        assert!(matches!(
+            Augmenter::new(
+                5,
+                5,
+                vec![Augmentation::Noise {
+                    probability: 1.5,
+                    std: 1.0
+                }]
+            ),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_batch_columns_are_augmented_independently() {
+        let augmenter = always(Augmentation::Noise {
+            probability: 1.0,
+            std: 0.1,
+        });
+        let mut x = Matrix::new(25, 2, vec![0.0; 50]);
+        augmenter
+            .apply_batch(&mut x, &mut SeededRng::new(8))
+            .unwrap();
+        assert_ne!(x.get(0, 0), x.get(0, 1));
+
+        let mut wrong = Matrix::new(24, 1, vec![0.0; 24]);
+        assert!(matches!(
+            augmenter.apply_batch(&mut wrong, &mut SeededRng::new(8)),
+            Err(Error::Shape(_))
+        ));
+    }
+
+    #[test]
+    fn test_transposed_batch() {
+        let augmenter = always(Augmentation::Translate {
+            probability: 1.0,
+            pixels: 1.0,
+        });
+        let images = [dot(1, 1), dot(3, 4)];
+        let mut x = Matrix::from_columns(&[
+            Matrix::new(25, 1, images[0].clone()),
+            Matrix::new(25, 1, images[1].clone()),
+        ])
+        .unwrap();
+        // The same batch stored image by image
+        let mut t = Matrix::new(2, 25, images.concat());
+        t.transpose();
+
+        augmenter
+            .apply_batch(&mut x, &mut SeededRng::new(5))
+            .unwrap();
+        augmenter
+            .apply_batch(&mut t, &mut SeededRng::new(5))
+            .unwrap();
+        for p in 0..25 {
+            assert_eq!((x.get(p, 0), x.get(p, 1)), (t.get(p, 0), t.get(p, 1)));
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::data::definition::CIFAR10;
+use crate::error::{Error, Result};
+use std::io::{BufReader, Read};
+use std::{fs::File, path::Path};
+
+// A label byte followed by the red, green and blue planes of a 32x32 image, each row by row
+const RECORD: usize = 1 + 3 * 32 * 32;
+
+// Reads a CIFAR-10 binary batch file (`data_batch_1.bin` ... `test_batch.bin`) into the pixels of
+// its images, each image laid out as (3, 32, 32), and their labels.
+pub fn read(path: impl AsRef<Path>) -> Result<(Vec<u8>, Vec<u8>)> {
+    read_from(BufReader::new(File::open(path)?))
+}
+
+pub fn read_from(mut reader: impl Read) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    if !bytes.len().is_multiple_of(RECORD) {
+        return Err(Error::Format(format!(
+            "CIFAR-10 file of {} bytes is not made of {} byte records",
+            bytes.len(),
+            RECORD
+        )));
+    }
+
+    let n = bytes.len() / RECORD;
+    let mut labels = Vec::with_capacity(n);
+    let mut pixels = Vec::with_capacity(n * (RECORD - 1));
+    for record in bytes.chunks_exact(RECORD) {
+        if record[0] as usize >= CIFAR10.classes() {
+            return Err(Error::Format(format!(
+                "Label {} is not a CIFAR-10 class",
+                record[0]
+            )));
+        }
+        labels.push(record[0]);
+        pixels.extend_from_slice(&record[1..]);
+    }
+    Ok((pixels, labels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(label: u8, value: u8) -> Vec<u8> {
+        let mut record = vec![value; RECORD];
+        record[0] = label;
+        record
+    }
+
+    #[test]
+    fn test_read_records() {
+        let bytes = [record(3, 10), record(9, 20)].concat();
+        let (pixels, labels) = read_from(&bytes[..]).unwrap();
+
+        assert_eq!(pixels.len(), 2 * 3072);
+        assert_eq!((pixels[3071], pixels[3072], pixels[6143]), (10, 20, 20));
+        assert_eq!(labels, vec![3, 9]);
+    }
+
+    #[test]
+    fn test_bad_files() {
+        let truncated = &record(1, 0)[..100];
+        assert!(matches!(read_from(truncated), Err(Error::Format(_))));
+        assert!(matches!(
+            read_from(&record(10, 0)[..]),
+            Err(Error::Format(_))
+        ));
+        assert!(matches!(
+            read("./no/such/data_batch_1.bin"),
+            Err(Error::Io(_))
+        ));
+    }
+}
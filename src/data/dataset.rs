@@ -0,0 +1,635 @@
+use crate::data::augmentation::Augmenter;
+use crate::data::cifar;
+use crate::data::definition::{Definition, CIFAR10};
+use crate::data::idx_parser::{self, IdxArray, IdxData};
+use crate::data::labels::{label_class, LabelEncoder};
+use crate::data::preprocessing::Pipeline;
+use crate::error::{Error, Result};
+use crate::math::matrix::Matrix;
+use crate::math::random::SeededRng;
+use rand::seq::SliceRandom;
+use std::path::Path;
+
+// Labelled samples that can be looked up by index, e.g. to be batched by a `DataLoader`.
+pub trait Dataset {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Input (features x 1) and label (classes x 1, or 1 x 1 class index) of sample `index`.
+    // Panics when `index` is out of range, like indexing a slice.
+    fn get(&self, index: usize) -> (Matrix, Matrix);
+
+    // Class of sample `index`, used for stratified splits. Datasets storing class indices should
+    // override this instead of building the label.
+    fn class(&self, index: usize) -> usize {
+        label_class(&self.get(index).1, 0)
+    }
+
+    // Samples at `indices` stacked into (features x n) inputs and labels. Datasets with a compact
+    // storage should override this to build the batch without going through `get`.
+    fn batch(&self, indices: &[usize]) -> Result<(Matrix, Matrix)> {
+        let (inputs, labels): (Vec<Matrix>, Vec<Matrix>) =
+            indices.iter().map(|&i| self.get(i)).unzip();
+        Ok((
+            Matrix::from_columns(&inputs)?,
+            Matrix::from_columns(&labels)?,
+        ))
+    }
+
+    // A training batch, randomly augmented with `rng` when the dataset has augmentation set up
+    fn augmented_batch(&self, indices: &[usize], rng: &mut SeededRng) -> Result<(Matrix, Matrix)> {
+        let _ = rng;
+        self.batch(indices)
+    }
+}
+
+impl<D: Dataset + ?Sized> Dataset for &D {
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn get(&self, index: usize) -> (Matrix, Matrix) {
+        (**self).get(index)
+    }
+
+    fn class(&self, index: usize) -> usize {
+        (**self).class(index)
+    }
+
+    fn batch(&self, indices: &[usize]) -> Result<(Matrix, Matrix)> {
+        (**self).batch(indices)
+    }
+
+    fn augmented_batch(&self, indices: &[usize], rng: &mut SeededRng) -> Result<(Matrix, Matrix)> {
+        (**self).augmented_batch(indices, rng)
+    }
+}
+
+// Inputs and labels as (features x 1) and (classes x 1) columns, e.g. from `idx_parser::parse`.
+#[derive(Clone, Copy)]
+pub struct Samples<'a> {
+    pub inputs: &'a [Matrix],
+    pub labels: &'a [Matrix],
+}
+
+impl<'a> Samples<'a> {
+    pub fn new(inputs: &'a [Matrix], labels: &'a [Matrix]) -> Result<Self> {
+        if inputs.len() != labels.len() {
+            return Err(Error::Shape(format!(
+                "{} inputs but {} labels, every input needs exactly one label",
+                inputs.len(),
+                labels.len()
+            )));
+        }
+        Ok(Samples { inputs, labels })
+    }
+}
+
+impl Dataset for Samples<'_> {
+    fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    fn get(&self, index: usize) -> (Matrix, Matrix) {
+        (self.inputs[index].clone(), self.labels[index].clone())
+    }
+}
+
+// The samples of `dataset` at `indices`, in that order.
+pub struct Subset<'a, D: Dataset + ?Sized> {
+    dataset: &'a D,
+    indices: Vec<usize>,
+}
+
+impl<'a, D: Dataset + ?Sized> Subset<'a, D> {
+    pub fn new(dataset: &'a D, indices: Vec<usize>) -> Self {
+        Subset { dataset, indices }
+    }
+
+    pub fn indices(&self) -> &[usize] {
+        &self.indices
+    }
+
+    fn map(&self, indices: &[usize]) -> Vec<usize> {
+        indices.iter().map(|&i| self.indices[i]).collect()
+    }
+}
+
+impl<D: Dataset + ?Sized> Dataset for Subset<'_, D> {
+    fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    fn get(&self, index: usize) -> (Matrix, Matrix) {
+        self.dataset.get(self.indices[index])
+    }
+
+    fn class(&self, index: usize) -> usize {
+        self.dataset.class(self.indices[index])
+    }
+
+    fn batch(&self, indices: &[usize]) -> Result<(Matrix, Matrix)> {
+        self.dataset.batch(&self.map(indices))
+    }
+
+    fn augmented_batch(&self, indices: &[usize], rng: &mut SeededRng) -> Result<(Matrix, Matrix)> {
+        self.dataset.augmented_batch(&self.map(indices), rng)
+    }
+}
+
+// Holds out the last `fraction` of the samples, in order
+pub fn tail_split<D: Dataset + ?Sized>(
+    dataset: &D,
+    fraction: f64,
+) -> (Subset<'_, D>, Subset<'_, D>) {
+    let at = dataset.len() - held_out(dataset.len(), fraction);
+    (
+        Subset::new(dataset, (0..at).collect()),
+        Subset::new(dataset, (at..dataset.len()).collect()),
+    )
+}
+
+// Holds out a random `fraction` of the samples, the same ones for the same seed
+pub fn random_split<D: Dataset + ?Sized>(
+    dataset: &D,
+    fraction: f64,
+    seed: u64,
+) -> (Subset<'_, D>, Subset<'_, D>) {
+    let mut order: Vec<usize> = (0..dataset.len()).collect();
+    order.shuffle(&mut SeededRng::new(seed));
+    let validation = order.split_off(dataset.len() - held_out(dataset.len(), fraction));
+    (
+        Subset::new(dataset, sorted(order)),
+        Subset::new(dataset, sorted(validation)),
+    )
+}
+
+// Holds out a random `fraction` of every class, so both sides keep the class balance of the dataset
+pub fn stratified_split<D: Dataset + ?Sized>(
+    dataset: &D,
+    fraction: f64,
+    seed: u64,
+) -> (Subset<'_, D>, Subset<'_, D>) {
+    let mut classes: Vec<Vec<usize>> = vec![];
+    for i in 0..dataset.len() {
+        let class = dataset.class(i);
+        if class >= classes.len() {
+            classes.resize(class + 1, vec![]);
+        }
+        classes[class].push(i);
+    }
+
+    let mut rng = SeededRng::new(seed);
+    let (mut train, mut validation) = (vec![], vec![]);
+    for mut members in classes {
+        members.shuffle(&mut rng);
+        let held = members.split_off(members.len() - held_out(members.len(), fraction));
+        train.extend(members);
+        validation.extend(held);
+    }
+    (
+        Subset::new(dataset, sorted(train)),
+        Subset::new(dataset, sorted(validation)),
+    )
+}
+
+fn held_out(len: usize, fraction: f64) -> usize {
+    ((len as f64 * fraction).round() as usize).min(len)
+}
+
+fn sorted(mut indices: Vec<usize>) -> Vec<usize> {
+    indices.sort_unstable();
+    indices
+}
+
+// Images and class labels of a dataset described by a `Definition`, e.g. MNIST from a pair of IDX
+// files. The pixels stay in one contiguous byte buffer, a batch is only converted to f64 in [0, 1],
+// flattened into (pixels x 1) columns and preprocessed when it is drawn. Training batches are
+// augmented before preprocessing when an augmenter is set. Labels are one-hot.
+pub struct ImageDataset {
+    definition: Definition,
+    // Every image back to back, channel after channel, each channel row by row
+    pixels: Vec<u8>,
+    // Classes, counted from 0
+    labels: Vec<u8>,
+    pipeline: Option<Pipeline>,
+    augmenter: Option<Augmenter>,
+    encoder: LabelEncoder,
+}
+
+impl ImageDataset {
+    // An IDX image and label file pair, as MNIST, Fashion-MNIST, KMNIST and EMNIST are distributed
+    pub fn load_idx(
+        definition: Definition,
+        images: impl AsRef<Path>,
+        labels: impl AsRef<Path>,
+    ) -> Result<ImageDataset> {
+        ImageDataset::new(
+            definition,
+            idx_parser::read(images)?,
+            idx_parser::read(labels)?,
+        )
+    }
+
+    // CIFAR-10 binary batch files, e.g. the five `data_batch_*.bin` for training
+    pub fn load_cifar10(paths: &[impl AsRef<Path>]) -> Result<ImageDataset> {
+        let (mut pixels, mut labels) = (vec![], vec![]);
+        for path in paths {
+            let (batch_pixels, batch_labels) = cifar::read(path)?;
+            pixels.extend(batch_pixels);
+            labels.extend(batch_labels);
+        }
+        let n = labels.len();
+        ImageDataset::new(
+            CIFAR10,
+            IdxArray {
+                shape: vec![n, CIFAR10.channels, CIFAR10.height, CIFAR10.width],
+                data: IdxData::U8(pixels),
+            },
+            IdxArray {
+                shape: vec![n],
+                data: IdxData::U8(labels),
+            },
+        )
+    }
+
+    pub fn new(definition: Definition, images: IdxArray, labels: IdxArray) -> Result<ImageDataset> {
+        if labels.shape.len() != 1 {
+            return Err(Error::Format(format!(
+                "Labels must be 1-dimensional, got shape {:?}",
+                labels.shape
+            )));
+        }
+        if images.items() != labels.items() {
+            return Err(Error::Shape(format!(
+                "{} images but {} labels",
+                images.items(),
+                labels.items()
+            )));
+        }
+        if images.item_size() != definition.image_size() {
+            return Err(Error::Shape(format!(
+                "{} images are {}x{}x{}, got shape {:?}",
+                definition.name,
+                definition.channels,
+                definition.height,
+                definition.width,
+                images.shape
+            )));
+        }
+        let mut pixels = match images.data {
+            IdxData::U8(pixels) => pixels,
+            data => {
+                return Err(Error::Format(format!(
+                    "Images must be unsigned bytes, got {:?}",
+                    data.dtype()
+                )))
+            }
+        };
+        if definition.transposed {
+            transpose_images(&mut pixels, &definition);
+        }
+        let first = definition.first_label as f64;
+        let classes = definition.classes() as f64;
+        let labels = labels
+            .data
+            .to_f64(0..labels.items())
+            .into_iter()
+            .map(|label| match label - first {
+                class if (0.0..classes).contains(&class) && class.fract() == 0.0 => Ok(class as u8),
+                _ => Err(Error::Format(format!(
+                    "Label {} is not a class of {}",
+                    label, definition.name
+                ))),
+            })
+            .collect::<Result<_>>()?;
+        Ok(ImageDataset {
+            pixels,
+            labels,
+            pipeline: None,
+            augmenter: None,
+            encoder: LabelEncoder::from_definition(&definition),
+            definition,
+        })
+    }
+
+    pub fn definition(&self) -> &Definition {
+        &self.definition
+    }
+
+    // Encodes the labels of drawn batches, named after the classes of the definition
+    pub fn encoder(&self) -> &LabelEncoder {
+        &self.encoder
+    }
+
+    // Replaces the label encoder, e.g. with one that smooths the labels
+    pub fn set_encoder(&mut self, encoder: LabelEncoder) -> Result<()> {
+        if encoder.classes() != self.definition.classes() {
+            return Err(Error::Shape(format!(
+                "{} has {} classes, the encoder {}",
+                self.definition.name,
+                self.definition.classes(),
+                encoder.classes()
+            )));
+        }
+        self.encoder = encoder;
+        Ok(())
+    }
+
+    // Pixels per image
+    pub fn image_size(&self) -> usize {
+        self.definition.image_size()
+    }
+
+    pub fn pixels(&self, index: usize) -> &[u8] {
+        let size = self.image_size();
+        &self.pixels[index * size..(index + 1) * size]
+    }
+
+    pub fn labels(&self) -> &[u8] {
+        &self.labels
+    }
+
+    // Images at `indices` as a (pixels x n) matrix in [0, 1], before any preprocessing
+    pub fn images(&self, indices: &[usize]) -> Matrix {
+        let n = indices.len();
+        let mut data = vec![0.0; self.image_size() * n];
+        for (j, &i) in indices.iter().enumerate() {
+            for (p, &pixel) in self.pixels(i).iter().enumerate() {
+                data[p * n + j] = pixel as f64 / 255.0;
+            }
+        }
+        Matrix::new(self.image_size(), n, data)
+    }
+
+    // Preprocesses every batch drawn from now on with `pipeline`
+    pub fn transform(&mut self, pipeline: Pipeline) -> Result<()> {
+        let size = self.image_size();
+        pipeline.apply(&Matrix::new(size, 1, vec![0.0; size]))?;
+        self.pipeline = Some(pipeline);
+        Ok(())
+    }
+
+    // Augments every training batch drawn from now on with `augmenter`
+    pub fn augment(&mut self, augmenter: Augmenter) -> Result<()> {
+        if augmenter.image_size() != self.image_size() {
+            return Err(Error::Shape(format!(
+                "Images have {} pixels but the augmenter works on {}",
+                self.image_size(),
+                augmenter.image_size()
+            )));
+        }
+        self.augmenter = Some(augmenter);
+        Ok(())
+    }
+
+    fn preprocessed(&self, mut x: Matrix, indices: &[usize]) -> Result<(Matrix, Matrix)> {
+        if let Some(pipeline) = &self.pipeline {
+            x = pipeline.apply(&x)?;
+        }
+        let classes: Vec<usize> = indices.iter().map(|&i| self.labels[i] as usize).collect();
+        Ok((x, self.encoder.encode_batch(&classes)?))
+    }
+}
+
+impl Dataset for ImageDataset {
+    fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    fn get(&self, index: usize) -> (Matrix, Matrix) {
+        self.batch(&[index])
+            .expect("The pipeline was checked against the image size")
+    }
+
+    fn class(&self, index: usize) -> usize {
+        self.labels[index] as usize
+    }
+
+    fn batch(&self, indices: &[usize]) -> Result<(Matrix, Matrix)> {
+        self.preprocessed(self.images(indices), indices)
+    }
+
+    fn augmented_batch(&self, indices: &[usize], rng: &mut SeededRng) -> Result<(Matrix, Matrix)> {
+        let mut x = self.images(indices);
+        if let Some(augmenter) = &self.augmenter {
+            augmenter.apply_batch(&mut x, rng)?;
+        }
+        self.preprocessed(x, indices)
+    }
+}
+
+// Swaps rows and columns of every channel of every image
+fn transpose_images(pixels: &mut [u8], definition: &Definition) {
+    let (h, w) = (definition.height, definition.width);
+    for channel in pixels.chunks_exact_mut(h * w) {
+        let original = channel.to_vec();
+        for y in 0..h {
+            for x in 0..w {
+                channel[y * w + x] = original[x * h + y];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::preprocessing::Step;
+
+    const TINY: Definition = Definition {
+        name: "tiny",
+        channels: 1,
+        height: 2,
+        width: 2,
+        class_names: &["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"],
+        first_label: 0,
+        transposed: false,
+    };
+
+    // 2x2 images whose pixels are all the label
+    fn image_dataset(labels: &[u8]) -> ImageDataset {
+        let pixels = labels.iter().flat_map(|&l| [l; 4]).collect();
+        ImageDataset::new(
+            TINY,
+            IdxArray {
+                shape: vec![labels.len(), 2, 2],
+                data: IdxData::U8(pixels),
+            },
+            IdxArray {
+                shape: vec![labels.len()],
+                data: IdxData::U8(labels.to_vec()),
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_image_dataset_samples() {
+        let data = image_dataset(&[3, 0, 9]);
+        assert_eq!(data.len(), 3);
+
+        let (x, y) = data.get(2);
+        assert_eq!((x.rows, x.cols), (4, 1));
+        assert_eq!(x.data(), &[9.0 / 255.0; 4]);
+        assert_eq!(y.data(), data.encoder().encode(9).unwrap().data());
+        assert_eq!(data.class(0), 3);
+    }
+
+    #[test]
+    fn test_image_dataset_bad_labels() {
+        let images = IdxArray {
+            shape: vec![2, 2, 2],
+            data: IdxData::U8(vec![0; 8]),
+        };
+        let labels = |data: Vec<u8>| IdxArray {
+            shape: vec![data.len()],
+            data: IdxData::U8(data),
+        };
+        assert!(matches!(
+            ImageDataset::new(TINY, images.clone(), labels(vec![1, 10])),
+            Err(Error::Format(_))
+        ));
+        assert!(matches!(
+            ImageDataset::new(TINY, images, labels(vec![1])),
+            Err(Error::Shape(_))
+        ));
+        let floats = IdxArray {
+            shape: vec![2, 2, 2],
+            data: IdxData::F32(vec![0.0; 8]),
+        };
+        assert!(matches!(
+            ImageDataset::new(TINY, floats, labels(vec![1, 2])),
+            Err(Error::Format(_))
+        ));
+    }
+
+    #[test]
+    fn test_splits() {
+        let data = image_dataset(&[0; 10]);
+        let (train, validation) = tail_split(&data, 0.3);
+        assert_eq!(train.indices(), &[0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(validation.indices(), &[7, 8, 9]);
+
+        let (train, validation) = random_split(&data, 0.3, 5);
+        let mut all = [train.indices(), validation.indices()].concat();
+        all.sort();
+        assert_eq!(validation.len(), 3);
+        assert_eq!(all, (0..10).collect::<Vec<_>>());
+        assert_eq!(
+            random_split(&data, 0.3, 5).1.indices(),
+            validation.indices()
+        );
+    }
+
+    #[test]
+    fn test_stratified_split_keeps_class_balance() {
+        let labels: Vec<u8> = (0..100).map(|i| if i % 4 == 0 { 1 } else { 0 }).collect();
+        let data = image_dataset(&labels);
+        let (train, validation) = stratified_split(&data, 0.2, 11);
+
+        let count =
+            |s: &Subset<ImageDataset>, class| (0..s.len()).filter(|&i| s.class(i) == class).count();
+        assert_eq!((count(&validation, 0), count(&validation, 1)), (15, 5));
+        assert_eq!((count(&train, 0), count(&train, 1)), (60, 20));
+        assert_eq!(
+            stratified_split(&data, 0.2, 11).1.indices(),
+            validation.indices()
+        );
+    }
+
+    #[test]
+    fn test_image_dataset_batches_are_built_from_bytes() {
+        let mut data = image_dataset(&[1, 2, 3, 4]);
+        assert_eq!(data.pixels(1), &[2; 4]);
+
+        let (x, y) = data.batch(&[3, 0]).unwrap();
+        assert_eq!((x.rows, x.cols), (4, 2));
+        assert_eq!(x.get(0, 0), 4.0 / 255.0);
+        assert_eq!(x.get(3, 1), 1.0 / 255.0);
+        assert_eq!((y.get(4, 0), y.get(1, 1), y.get(1, 0)), (1.0, 1.0, 0.0));
+
+        // Stacking single samples gives the same batch
+        let (a, _) = data.get(3);
+        assert_eq!(a.get(0, 0), x.get(0, 0));
+
+        let pipeline = Pipeline::fit_batch(&[Step::ZScore], &data.images(&[0, 1, 2, 3])).unwrap();
+        data.transform(pipeline).unwrap();
+        let (x, _) = data.batch(&[0, 1, 2, 3]).unwrap();
+        assert!(x.data().iter().sum::<f64>().abs() < 1e-9);
+
+        let wrong = Pipeline::fit(&[Step::PerFeature], &[Matrix::new(2, 1, vec![0.0, 1.0])]);
+        assert!(data.transform(wrong.unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_image_dataset_augments_only_training_batches() {
+        use crate::data::augmentation::Augmentation;
+
+        let mut data = image_dataset(&[5, 6]);
+        let noise = Augmentation::Noise {
+            probability: 1.0,
+            std: 0.1,
+        };
+        assert!(data
+            .augment(Augmenter::mnist(vec![noise]).unwrap())
+            .is_err());
+        data.augment(Augmenter::new(2, 2, vec![noise]).unwrap())
+            .unwrap();
+
+        let (plain, _) = data.batch(&[0, 1]).unwrap();
+        let (augmented, y) = data
+            .augmented_batch(&[0, 1], &mut SeededRng::new(1))
+            .unwrap();
+        assert_eq!(plain.data(), data.images(&[0, 1]).data());
+        assert_ne!(augmented.data(), plain.data());
+        assert_eq!(y.get(5, 0), 1.0);
+    }
+
+    #[test]
+    fn test_definition_shape_labels_and_layout() {
+        use crate::data::definition::{EMNIST_LETTERS, MNIST};
+
+        let labels = |data: Vec<u8>| IdxArray {
+            shape: vec![data.len()],
+            data: IdxData::U8(data),
+        };
+        let wrong_size = IdxArray {
+            shape: vec![1, 2, 2],
+            data: IdxData::U8(vec![0; 4]),
+        };
+        assert!(matches!(
+            ImageDataset::new(MNIST, wrong_size, labels(vec![0])),
+            Err(Error::Shape(_))
+        ));
+
+        // EMNIST letters count from 1 and store images transposed
+        let mut pixels = vec![0; 784];
+        pixels[1] = 255;
+        let letters = ImageDataset::new(
+            EMNIST_LETTERS,
+            IdxArray {
+                shape: vec![1, 28, 28],
+                data: IdxData::U8(pixels),
+            },
+            labels(vec![26]),
+        )
+        .unwrap();
+        let (x, y) = letters.get(0);
+        assert_eq!(letters.class(0), 25);
+        assert_eq!((y.rows, y.get(25, 0)), (26, 1.0));
+        assert_eq!(x.get(28, 0), 1.0);
+        assert!(ImageDataset::new(
+            EMNIST_LETTERS,
+            IdxArray {
+                shape: vec![1, 28, 28],
+                data: IdxData::U8(vec![0; 784]),
+            },
+            labels(vec![0]),
+        )
+        .is_err());
+    }
+}
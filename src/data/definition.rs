@@ -0,0 +1,160 @@
+// What a dataset's files contain: the shape of its images and the classes of its labels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Definition {
+    pub name: &'static str,
+    pub channels: usize,
+    pub height: usize,
+    pub width: usize,
+    // Indexed by class, so also gives the class count
+    pub class_names: &'static [&'static str],
+    // Label value of class 0 in the files, EMNIST letters count from 1
+    pub first_label: u8,
+    // Images are stored column by column and are transposed on load, as in every EMNIST split
+    pub transposed: bool,
+}
+
+impl Definition {
+    pub fn classes(&self) -> usize {
+        self.class_names.len()
+    }
+
+    // Values per image, channel after channel
+    pub fn image_size(&self) -> usize {
+        self.channels * self.height * self.width
+    }
+
+    pub fn by_name(name: &str) -> Option<&'static Definition> {
+        DEFINITIONS
+            .iter()
+            .copied()
+            .find(|d| d.name.eq_ignore_ascii_case(name))
+    }
+}
+
+const DIGITS: [&str; 10] = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"];
+
+const fn grayscale(
+    name: &'static str,
+    class_names: &'static [&'static str],
+    first_label: u8,
+    transposed: bool,
+) -> Definition {
+    Definition {
+        name,
+        channels: 1,
+        height: 28,
+        width: 28,
+        class_names,
+        first_label,
+        transposed,
+    }
+}
+
+pub const MNIST: Definition = grayscale("mnist", &DIGITS, 0, false);
+
+pub const FASHION_MNIST: Definition = grayscale(
+    "fashion-mnist",
+    &[
+        "T-shirt/top",
+        "Trouser",
+        "Pullover",
+        "Dress",
+        "Coat",
+        "Sandal",
+        "Shirt",
+        "Sneaker",
+        "Bag",
+        "Ankle boot",
+    ],
+    0,
+    false,
+);
+
+// Kuzushiji-MNIST, one cursive hiragana per class
+pub const KMNIST: Definition = grayscale(
+    "kmnist",
+    &["o", "ki", "su", "tsu", "na", "ha", "ma", "ya", "re", "wo"],
+    0,
+    false,
+);
+
+// Digits, upper case letters and the lower case letters that don't look like their upper case
+pub const EMNIST_BALANCED: Definition = grayscale(
+    "emnist-balanced",
+    &[
+        "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "A", "B", "C", "D", "E", "F", "G", "H",
+        "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T", "U", "V", "W", "X", "Y", "Z",
+        "a", "b", "d", "e", "f", "g", "h", "n", "q", "r", "t",
+    ],
+    0,
+    true,
+);
+
+// Upper and lower case merged into one class per letter
+pub const EMNIST_LETTERS: Definition = grayscale(
+    "emnist-letters",
+    &[
+        "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R",
+        "S", "T", "U", "V", "W", "X", "Y", "Z",
+    ],
+    1,
+    true,
+);
+
+pub const EMNIST_BYCLASS: Definition = grayscale(
+    "emnist-byclass",
+    &[
+        "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "A", "B", "C", "D", "E", "F", "G", "H",
+        "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T", "U", "V", "W", "X", "Y", "Z",
+        "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r",
+        "s", "t", "u", "v", "w", "x", "y", "z",
+    ],
+    0,
+    true,
+);
+
+pub const CIFAR10: Definition = Definition {
+    name: "cifar-10",
+    channels: 3,
+    height: 32,
+    width: 32,
+    class_names: &[
+        "airplane",
+        "automobile",
+        "bird",
+        "cat",
+        "deer",
+        "dog",
+        "frog",
+        "horse",
+        "ship",
+        "truck",
+    ],
+    first_label: 0,
+    transposed: false,
+};
+
+pub const DEFINITIONS: [&Definition; 7] = [
+    &MNIST,
+    &FASHION_MNIST,
+    &KMNIST,
+    &EMNIST_BALANCED,
+    &EMNIST_LETTERS,
+    &EMNIST_BYCLASS,
+    &CIFAR10,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_definitions() {
+        let classes: Vec<usize> = DEFINITIONS.iter().map(|d| d.classes()).collect();
+        assert_eq!(classes, vec![10, 10, 10, 47, 26, 62, 10]);
+        assert_eq!(MNIST.image_size(), 784);
+        assert_eq!(CIFAR10.image_size(), 3072);
+        assert_eq!(Definition::by_name("Fashion-MNIST"), Some(&FASHION_MNIST));
+        assert_eq!(Definition::by_name("svhn"), None);
+    }
+}
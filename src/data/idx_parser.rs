@@ -1,104 +1,316 @@
+use crate::data::definition::MNIST;
+use crate::data::labels::LabelEncoder;
+use crate::error::{Error, Result};
 use crate::math::matrix::Matrix;
-use std::{fs::File, io::BufReader, io::Read};
-
-pub fn parse(file_path: &str) -> Vec<Matrix> {
-    match File::open(file_path) {
-        Ok(file) => {
-            let mut br = BufReader::new(file);
-            let mut magic_num = [0_u8; 4];
-            let _ = br.read(&mut magic_num[..]).unwrap();
-            // We are reading MNIST idx file which has ubyte data, so no need to check for data type.
-            // Only checking for dimensions as images have 3 dimensions and labels has 1
-            let dim_count = magic_num[3] as usize;
-
-            let mut y: Vec<Matrix> = vec![];
-            if dim_count == 1 {
-                // Labels
-                let mut first_dim = [0_u8; 4];
-                let _ = br.read(&mut first_dim).unwrap();
-                for _ in 0..u32::from_be_bytes(first_dim) {
-                    let mut d = [0_u8; 1];
-                    let _ = br.read_exact(&mut d[..]).unwrap();
-                    y.push(one_hot(d[0]));
-                }
-            } else {
-                // Image matrices
-                let mut first_dim = [0_u8; 4];
-                let _ = br.read(&mut first_dim).unwrap();
-                // Flatten vector of the 28x28 image
-                for _ in 0..u32::from_be_bytes(first_dim) {
-                    let mut buf_d = [0_u8; 784];
-                    let _ = br.read_exact(&mut buf_d[..]).unwrap();
-                    y.push(Matrix::new(
-                        784,
-                        1,
-                        Vec::from(buf_d).into_iter().map(|x| x as f64).collect(),
-                    ));
-                }
-            }
+use std::io::{self, BufReader, Read};
+use std::{fs::File, path::Path};
+
+// Element type, the third byte of the magic number
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataType {
+    U8,
+    I8,
+    I16,
+    I32,
+    F32,
+    F64,
+}
+
+impl DataType {
+    fn from_code(code: u8) -> Option<DataType> {
+        match code {
+            0x08 => Some(DataType::U8),
+            0x09 => Some(DataType::I8),
+            0x0B => Some(DataType::I16),
+            0x0C => Some(DataType::I32),
+            0x0D => Some(DataType::F32),
+            0x0E => Some(DataType::F64),
+            _ => None,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            DataType::U8 | DataType::I8 => 1,
+            DataType::I16 => 2,
+            DataType::I32 | DataType::F32 => 4,
+            DataType::F64 => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdxData {
+    U8(Vec<u8>),
+    I8(Vec<i8>),
+    I16(Vec<i16>),
+    I32(Vec<i32>),
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+}
+
+// Contents of an IDX file, elements in row-major order of `shape`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdxArray {
+    pub shape: Vec<usize>,
+    pub data: IdxData,
+}
+
+impl IdxData {
+    pub fn dtype(&self) -> DataType {
+        match self {
+            IdxData::U8(_) => DataType::U8,
+            IdxData::I8(_) => DataType::I8,
+            IdxData::I16(_) => DataType::I16,
+            IdxData::I32(_) => DataType::I32,
+            IdxData::F32(_) => DataType::F32,
+            IdxData::F64(_) => DataType::F64,
+        }
+    }
 
-            y
+    pub fn len(&self) -> usize {
+        match self {
+            IdxData::U8(d) => d.len(),
+            IdxData::I8(d) => d.len(),
+            IdxData::I16(d) => d.len(),
+            IdxData::I32(d) => d.len(),
+            IdxData::F32(d) => d.len(),
+            IdxData::F64(d) => d.len(),
         }
-        Err(e) => {
-            println!("{:?}", e);
-            panic!("Failed to open file");
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Elements `range` converted to f64
+    pub(crate) fn to_f64(&self, range: std::ops::Range<usize>) -> Vec<f64> {
+        match self {
+            IdxData::U8(d) => d[range].iter().map(|&v| v as f64).collect(),
+            IdxData::I8(d) => d[range].iter().map(|&v| v as f64).collect(),
+            IdxData::I16(d) => d[range].iter().map(|&v| v as f64).collect(),
+            IdxData::I32(d) => d[range].iter().map(|&v| v as f64).collect(),
+            IdxData::F32(d) => d[range].iter().map(|&v| v as f64).collect(),
+            IdxData::F64(d) => d[range].to_vec(),
         }
     }
 }
 
-#[inline]
-fn one_hot(num: u8) -> Matrix {
-    match num {
-        0 => Matrix::new(
-            10,
-            1,
-            vec![1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
-        ),
-        1 => Matrix::new(
-            10,
-            1,
-            vec![0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
-        ),
-        2 => Matrix::new(
-            10,
-            1,
-            vec![0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
-        ),
-        3 => Matrix::new(
-            10,
-            1,
-            vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
-        ),
-        4 => Matrix::new(
-            10,
-            1,
-            vec![0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
-        ),
-        5 => Matrix::new(
-            10,
-            1,
-            vec![0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0],
-        ),
-        6 => Matrix::new(
-            10,
-            1,
-            vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
-        ),
-        7 => Matrix::new(
-            10,
-            1,
-            vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0],
-        ),
-        8 => Matrix::new(
-            10,
-            1,
-            vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0],
-        ),
-        9 => Matrix::new(
-            10,
-            1,
-            vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0],
-        ),
-        _ => unreachable!(),
+impl IdxArray {
+    pub fn dtype(&self) -> DataType {
+        self.data.dtype()
+    }
+
+    // Number of items along the first dimension, e.g. images or labels
+    pub fn items(&self) -> usize {
+        self.shape.first().copied().unwrap_or(1)
+    }
+
+    // Elements per item, the product of every dimension after the first
+    pub fn item_size(&self) -> usize {
+        self.shape.iter().skip(1).product()
+    }
+
+    // Item `i` flattened into a (item_size x 1) column
+    pub fn item(&self, i: usize) -> Matrix {
+        let size = self.item_size();
+        Matrix::new(size, 1, self.data.to_f64(i * size..(i + 1) * size))
+    }
+}
+
+pub fn read(path: impl AsRef<Path>) -> Result<IdxArray> {
+    read_from(BufReader::new(File::open(path)?))
+}
+
+// Reads the header (two zero bytes, the data type, the number of dimensions and then every
+// dimension size as a big-endian u32) followed by the big-endian elements.
+pub fn read_from(mut reader: impl Read) -> Result<IdxArray> {
+    let mut magic = [0_u8; 4];
+    read_exact(&mut reader, &mut magic)?;
+    if magic[0] != 0 || magic[1] != 0 {
+        return Err(Error::Format("Not an IDX file".to_string()));
+    }
+    let dtype = DataType::from_code(magic[2])
+        .ok_or_else(|| Error::Format(format!("Unknown IDX data type 0x{:02X}", magic[2])))?;
+
+    let mut shape = Vec::with_capacity(magic[3] as usize);
+    for _ in 0..magic[3] {
+        let mut dim = [0_u8; 4];
+        read_exact(&mut reader, &mut dim)?;
+        shape.push(u32::from_be_bytes(dim) as usize);
+    }
+    let len = shape
+        .iter()
+        .try_fold(1_usize, |n, &d| n.checked_mul(d))
+        .and_then(|n| n.checked_mul(dtype.size()))
+        .ok_or_else(|| Error::Format("IDX dimensions are too large".to_string()))?;
+
+    // The header can't be trusted with the allocation, the buffer only grows with the data there is
+    let mut bytes = vec![];
+    reader.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Err(truncated());
+    }
+    if reader.read(&mut [0_u8; 1])? != 0 {
+        return Err(Error::Format(
+            "Trailing data after the IDX elements".to_string(),
+        ));
+    }
+
+    let data = match dtype {
+        DataType::U8 => IdxData::U8(bytes),
+        DataType::I8 => IdxData::I8(bytes.into_iter().map(|b| b as i8).collect()),
+        DataType::I16 => IdxData::I16(decode(&bytes, i16::from_be_bytes)),
+        DataType::I32 => IdxData::I32(decode(&bytes, i32::from_be_bytes)),
+        DataType::F32 => IdxData::F32(decode(&bytes, f32::from_be_bytes)),
+        DataType::F64 => IdxData::F64(decode(&bytes, f64::from_be_bytes)),
+    };
+    Ok(IdxArray { shape, data })
+}
+
+fn decode<T, const N: usize>(bytes: &[u8], from_be: fn([u8; N]) -> T) -> Vec<T> {
+    bytes
+        .chunks_exact(N)
+        .map(|c| from_be(c.try_into().unwrap()))
+        .collect()
+}
+
+// Running out of data is a format error, other read failures stay I/O errors
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => truncated(),
+        _ => Error::Io(e),
+    })
+}
+
+fn truncated() -> Error {
+    Error::Format("IDX file is truncated".to_string())
+}
+
+// MNIST style loading: a 1-d file is read as one-hot encoded digit labels, anything else as one flattened
+// (item_size x 1) column per item.
+pub fn parse(file_path: &str) -> Result<Vec<Matrix>> {
+    let array = read(file_path)?;
+
+    if array.shape.len() == 1 {
+        let encoder = LabelEncoder::from_definition(&MNIST);
+        (0..array.items())
+            .map(|i| match array.data.to_f64(i..i + 1)[0] {
+                label
+                    if (0.0..encoder.classes() as f64).contains(&label) && label.fract() == 0.0 =>
+                {
+                    encoder.encode(label as usize)
+                }
+                label => Err(Error::Format(format!("Label {} is not a digit", label))),
+            })
+            .collect()
+    } else {
+        Ok((0..array.items()).map(|i| array.item(i)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn idx(dtype: u8, shape: &[u32], elements: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0, 0, dtype, shape.len() as u8];
+        for d in shape {
+            bytes.extend_from_slice(&d.to_be_bytes());
+        }
+        bytes.extend_from_slice(elements);
+        bytes
+    }
+
+    #[test]
+    fn test_read_every_dtype() {
+        let be = |values: &[f64], encode: fn(f64) -> Vec<u8>| -> Vec<u8> {
+            values.iter().flat_map(|&v| encode(v)).collect()
+        };
+        let values = [1.0, -2.0, 3.0, -4.0, 5.0, -6.0];
+        let cases: [(u8, Vec<u8>, IdxData); 6] = [
+            (
+                0x08,
+                vec![1, 2, 3, 4, 5, 255],
+                IdxData::U8(vec![1, 2, 3, 4, 5, 255]),
+            ),
+            (
+                0x09,
+                be(&values, |v| (v as i8).to_be_bytes().to_vec()),
+                IdxData::I8(vec![1, -2, 3, -4, 5, -6]),
+            ),
+            (
+                0x0B,
+                be(&values, |v| (v as i16).to_be_bytes().to_vec()),
+                IdxData::I16(vec![1, -2, 3, -4, 5, -6]),
+            ),
+            (
+                0x0C,
+                be(&values, |v| (v as i32).to_be_bytes().to_vec()),
+                IdxData::I32(vec![1, -2, 3, -4, 5, -6]),
+            ),
+            (
+                0x0D,
+                be(&values, |v| (v as f32).to_be_bytes().to_vec()),
+                IdxData::F32(vec![1.0, -2.0, 3.0, -4.0, 5.0, -6.0]),
+            ),
+            (
+                0x0E,
+                be(&values, |v| v.to_be_bytes().to_vec()),
+                IdxData::F64(values.to_vec()),
+            ),
+        ];
+
+        for (code, elements, expected) in cases {
+            let array = read_from(&idx(code, &[2, 3], &elements)[..]).unwrap();
+            assert_eq!(array.shape, vec![2, 3]);
+            assert_eq!(array.data, expected);
+            assert_eq!(array.dtype().size() * 6, elements.len());
+        }
+    }
+
+    #[test]
+    fn test_items_of_any_shape() {
+        let elements: Vec<u8> = (0..24).collect();
+        let array = read_from(&idx(0x08, &[2, 3, 2, 2], &elements)[..]).unwrap();
+
+        assert_eq!(array.items(), 2);
+        assert_eq!(array.item_size(), 12);
+        let second = array.item(1);
+        assert_eq!((second.rows, second.cols), (12, 1));
+        assert_eq!(second.get(0, 0), 12.0);
+        assert_eq!(second.get(11, 0), 23.0);
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        let format_error = |bytes: &[u8]| matches!(read_from(bytes), Err(Error::Format(_)));
+        // Unknown type, bad magic, truncated elements, truncated header, trailing data, a shape far
+        // larger than the file
+        assert!(format_error(&idx(0x0A, &[1], &[0])));
+        assert!(format_error(&[1, 0, 8, 1, 0, 0, 0, 1, 0]));
+        assert!(format_error(&idx(0x08, &[4], &[1, 2])));
+        assert!(format_error(&[0, 0, 8, 2, 0, 0]));
+        assert!(format_error(&idx(0x08, &[1], &[1, 2])));
+        assert!(format_error(&[
+            0, 0, 8, 2, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF
+        ]));
+    }
+
+    #[test]
+    fn test_missing_file_is_an_io_error() {
+        assert!(matches!(
+            parse("./mnist_data/missing.idx1-ubyte"),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_mnist_labels() {
+        let labels = parse("./mnist_data/t10k-labels.idx1-ubyte").unwrap();
+        assert_eq!(labels.len(), 10000);
+        assert!(labels
+            .iter()
+            .all(|l| l.rows == 10 && l.data().iter().sum::<f64>() == 1.0));
     }
 }
@@ -0,0 +1,254 @@
+use crate::data::definition::Definition;
+use crate::error::{Error, Result};
+use crate::math::matrix::Matrix;
+use serde::{Deserialize, Serialize};
+
+// Turns class indices into network targets and network outputs back into classes and their names.
+// Saved with a model so its outputs can be read without knowing which dataset it was trained on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LabelEncoder {
+    // Indexed by class
+    names: Vec<String>,
+    // Share of every target spread evenly over the classes, 0 for hard 0 / 1 targets
+    #[serde(default)]
+    smoothing: f64,
+}
+
+impl LabelEncoder {
+    // `classes` classes named after their index
+    pub fn new(classes: usize) -> Result<Self> {
+        LabelEncoder::with_names((0..classes).map(|c| c.to_string()).collect())
+    }
+
+    pub fn with_names(names: Vec<String>) -> Result<Self> {
+        if names.is_empty() {
+            return Err(Error::InvalidArgument(
+                "A label encoder needs at least one class".to_string(),
+            ));
+        }
+        if let Some(name) = names
+            .iter()
+            .enumerate()
+            .find(|(i, name)| names[..*i].contains(name))
+            .map(|(_, name)| name)
+        {
+            return Err(Error::InvalidArgument(format!(
+                "Class name {:?} is used twice",
+                name
+            )));
+        }
+        Ok(LabelEncoder {
+            names,
+            smoothing: 0.0,
+        })
+    }
+
+    pub fn from_definition(definition: &Definition) -> Self {
+        LabelEncoder {
+            names: definition
+                .class_names
+                .iter()
+                .map(|n| n.to_string())
+                .collect(),
+            smoothing: 0.0,
+        }
+    }
+
+    // Moves `smoothing` of every target towards the uniform distribution, e.g. 0.1 turns the one-hot
+    // target of 10 classes into 0.91 for the label and 0.01 for every other class.
+    pub fn with_label_smoothing(mut self, smoothing: f64) -> Result<Self> {
+        if !(0.0..1.0).contains(&smoothing) {
+            return Err(Error::InvalidArgument(
+                "Label smoothing must be within [0, 1)".to_string(),
+            ));
+        }
+        self.smoothing = smoothing;
+        Ok(self)
+    }
+
+    pub fn classes(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn name(&self, class: usize) -> Option<&str> {
+        self.names.get(class).map(|n| n.as_str())
+    }
+
+    pub fn class_of(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == name)
+    }
+
+    // One-hot (classes x 1) target of `class`
+    pub fn encode(&self, class: usize) -> Result<Matrix> {
+        self.encode_batch(&[class])
+    }
+
+    // One-hot (classes x n) targets, a column per class index
+    pub fn encode_batch(&self, classes: &[usize]) -> Result<Matrix> {
+        let n = classes.len();
+        let off = self.smoothing / self.classes() as f64;
+        let mut data = vec![off; self.classes() * n];
+        for (j, &class) in classes.iter().enumerate() {
+            self.check(class)?;
+            data[class * n + j] += 1.0 - self.smoothing;
+        }
+        Ok(Matrix::new(self.classes(), n, data))
+    }
+
+    // Multi-hot (classes x 1) target with every class in `classes` set, for multi-label problems.
+    // Every class is a separate yes / no target, so smoothing moves it towards 0.5.
+    pub fn encode_multi(&self, classes: &[usize]) -> Result<Matrix> {
+        self.encode_multi_batch(&[classes])
+    }
+
+    pub fn encode_multi_batch(&self, samples: &[&[usize]]) -> Result<Matrix> {
+        let n = samples.len();
+        let off = self.smoothing / 2.0;
+        let mut data = vec![off; self.classes() * n];
+        for (j, classes) in samples.iter().enumerate() {
+            for &class in classes.iter() {
+                self.check(class)?;
+                data[class * n + j] = 1.0 - off;
+            }
+        }
+        Ok(Matrix::new(self.classes(), n, data))
+    }
+
+    // Most likely class of every column of a (classes x n) output
+    pub fn decode(&self, output: &Matrix) -> Result<Vec<usize>> {
+        self.check_output(output)?;
+        Ok((0..output.cols).map(|j| output.argmax_col(j)).collect())
+    }
+
+    // Name of the most likely class of every column
+    pub fn decode_names(&self, output: &Matrix) -> Result<Vec<&str>> {
+        Ok(self
+            .decode(output)?
+            .into_iter()
+            .map(|c| self.names[c].as_str())
+            .collect())
+    }
+
+    // Classes scoring at least `threshold` in every column, for multi-label outputs
+    pub fn decode_multi(&self, output: &Matrix, threshold: f64) -> Result<Vec<Vec<usize>>> {
+        self.check_output(output)?;
+        Ok((0..output.cols)
+            .map(|j| {
+                (0..output.rows)
+                    .filter(|&i| output.get(i, j) >= threshold)
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn check(&self, class: usize) -> Result<()> {
+        if class >= self.classes() {
+            return Err(Error::InvalidArgument(format!(
+                "Class {} is out of range for {} classes",
+                class,
+                self.classes()
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_output(&self, output: &Matrix) -> Result<()> {
+        if output.rows != self.classes() {
+            return Err(Error::Shape(format!(
+                "Output has {} rows but there are {} classes",
+                output.rows,
+                self.classes()
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Class of label column `j`: one-hot columns, or a single row of class indices (as used by `Nll`)
+// which also covers 0 / 1 binary targets.
+pub(crate) fn label_class(y: &Matrix, j: usize) -> usize {
+    match y.rows {
+        1 => y.get(0, j).round() as usize,
+        _ => y.argmax_col(j),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::definition::FASHION_MNIST;
+
+    fn close(a: &[f64], b: &[f64]) -> bool {
+        a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-12)
+    }
+
+    #[test]
+    fn test_one_hot_and_back() {
+        let encoder = LabelEncoder::new(4).unwrap();
+        let y = encoder.encode_batch(&[2, 0, 3]).unwrap();
+
+        assert_eq!((y.rows, y.cols), (4, 3));
+        assert_eq!((y.get(2, 0), y.get(0, 1), y.get(3, 2)), (1.0, 1.0, 1.0));
+        assert_eq!(y.data().iter().sum::<f64>(), 3.0);
+        assert_eq!(encoder.encode(1).unwrap().data(), &[0.0, 1.0, 0.0, 0.0]);
+        assert_eq!(encoder.decode(&y).unwrap(), vec![2, 0, 3]);
+        assert_eq!(encoder.decode_names(&y).unwrap(), vec!["2", "0", "3"]);
+
+        assert!(matches!(encoder.encode(4), Err(Error::InvalidArgument(_))));
+        let wrong = Matrix::new(3, 1, vec![0.0; 3]);
+        assert!(matches!(encoder.decode(&wrong), Err(Error::Shape(_))));
+    }
+
+    #[test]
+    fn test_label_smoothing() {
+        let encoder = LabelEncoder::new(10)
+            .unwrap()
+            .with_label_smoothing(0.1)
+            .unwrap();
+        let y = encoder.encode(3).unwrap();
+
+        assert!((y.get(3, 0) - 0.91).abs() < 1e-12);
+        assert!((y.get(0, 0) - 0.01).abs() < 1e-12);
+        assert!((y.data().iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert_eq!(encoder.decode(&y).unwrap(), vec![3]);
+        assert!(LabelEncoder::new(2)
+            .unwrap()
+            .with_label_smoothing(1.0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_multi_hot() {
+        let encoder = LabelEncoder::new(5).unwrap();
+        let y = encoder.encode_multi_batch(&[&[0, 3], &[], &[4]]).unwrap();
+        assert_eq!(
+            encoder.decode_multi(&y, 0.5).unwrap(),
+            vec![vec![0, 3], vec![], vec![4]]
+        );
+
+        let smoothed = encoder.with_label_smoothing(0.2).unwrap();
+        let y = smoothed.encode_multi(&[1]).unwrap();
+        assert!(close(y.data(), &[0.1, 0.9, 0.1, 0.1, 0.1]));
+    }
+
+    #[test]
+    fn test_names() {
+        let encoder = LabelEncoder::from_definition(&FASHION_MNIST);
+        assert_eq!(encoder.classes(), 10);
+        assert_eq!(encoder.name(9), Some("Ankle boot"));
+        assert_eq!(encoder.class_of("Bag"), Some(8));
+        assert_eq!(encoder.name(10), None);
+
+        let duplicate = vec!["cat".to_string(), "dog".to_string(), "cat".to_string()];
+        assert!(LabelEncoder::with_names(duplicate).is_err());
+        assert!(LabelEncoder::new(0).is_err());
+
+        let json = serde_json::to_string(&encoder).unwrap();
+        let restored: LabelEncoder = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, encoder);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::data::dataset::Dataset;
+use crate::error::{Error, Result};
+use crate::math::matrix::Matrix;
+use crate::math::random::SeededRng;
+use rand::seq::SliceRandom;
+
+const AUGMENTATION_STREAMS: u64 = 0x5EED_A064_3E47_0001;
+
+// Batches of a dataset stacked into (features x batch) inputs and (classes x batch) labels.
+pub struct DataLoader<'a, D: Dataset + ?Sized> {
+    dataset: &'a D,
+    batch_size: usize,
+    // Seed of the per-epoch shuffles, no shuffling when unset
+    shuffle: Option<u64>,
+    // Seed of the random augmentation of every batch, plain batches when unset
+    augment: Option<u64>,
+    drop_last: bool,
+}
+
+impl<'a, D: Dataset + ?Sized> DataLoader<'a, D> {
+    pub fn new(dataset: &'a D, batch_size: usize) -> Result<Self> {
+        if batch_size == 0 {
+            return Err(Error::InvalidArgument(
+                "Batch size must be at least 1".to_string(),
+            ));
+        }
+        Ok(DataLoader {
+            dataset,
+            batch_size,
+            shuffle: None,
+            augment: None,
+            drop_last: false,
+        })
+    }
+
+    // Reshuffles the samples every epoch. The order only depends on the seed and the epoch, so a
+    // resumed run sees the same batches.
+    pub fn shuffle(mut self, seed: u64) -> Self {
+        self.shuffle = Some(seed);
+        self
+    }
+
+    // Draws augmented batches, see `Dataset::augmented_batch`. Every batch gets its own stream of
+    // `seed` so each epoch sees fresh samples and skipping batches doesn't change later ones.
+    pub fn augment(mut self, seed: u64) -> Self {
+        self.augment = Some(seed);
+        self
+    }
+
+    // Skips the last batch of an epoch when it is smaller than the batch size
+    pub fn drop_last(mut self, drop_last: bool) -> Self {
+        self.drop_last = drop_last;
+        self
+    }
+
+    pub fn dataset(&self) -> &'a D {
+        self.dataset
+    }
+
+    // Batches per epoch
+    pub fn len(&self) -> usize {
+        match self.drop_last {
+            true => self.dataset.len() / self.batch_size,
+            false => self.dataset.len().div_ceil(self.batch_size),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Sample order of `epoch`
+    pub fn order(&self, epoch: usize) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.dataset.len()).collect();
+        if let Some(seed) = self.shuffle {
+            order.shuffle(&mut SeededRng::stream(seed, epoch as u64));
+        }
+        order
+    }
+
+    // The batches of `epoch`. Batches are only stacked when reached, skipping ahead is free.
+    pub fn epoch(&self, epoch: usize) -> Batches<'_, 'a, D> {
+        Batches {
+            loader: self,
+            order: self.order(epoch),
+            epoch,
+            next: 0,
+        }
+    }
+
+    // Stacks the samples at `indices`
+    pub fn batch(&self, indices: &[usize]) -> Result<(Matrix, Matrix)> {
+        self.dataset.batch(indices)
+    }
+}
+
+pub struct Batches<'l, 'a, D: Dataset + ?Sized> {
+    loader: &'l DataLoader<'a, D>,
+    order: Vec<usize>,
+    epoch: usize,
+    // Index of the next batch
+    next: usize,
+}
+
+impl<D: Dataset + ?Sized> Iterator for Batches<'_, '_, D> {
+    type Item = Result<(Matrix, Matrix)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.loader.len() {
+            return None;
+        }
+        let start = self.next * self.loader.batch_size;
+        let end = (start + self.loader.batch_size).min(self.order.len());
+        let indices = &self.order[start..end];
+        let batch = match self.loader.augment {
+            Some(seed) => {
+                // Kept apart from the shuffle streams, which are numbered by epoch alone
+                let id = (self.epoch as u64) << 32 | self.next as u64;
+                let mut rng = SeededRng::stream(seed ^ AUGMENTATION_STREAMS, id);
+                self.loader.dataset.augmented_batch(indices, &mut rng)
+            }
+            None => self.loader.batch(indices),
+        };
+        self.next += 1;
+        Some(batch)
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.next = self.next.saturating_add(n);
+        self.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.loader.len().saturating_sub(self.next);
+        (left, Some(left))
+    }
+}
+
+impl<D: Dataset + ?Sized> ExactSizeIterator for Batches<'_, '_, D> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::dataset::Samples;
+
+    // Sample i has input [i] and label [i]
+    fn samples(n: usize) -> (Vec<Matrix>, Vec<Matrix>) {
+        let column = |i| Matrix::new(1, 1, vec![i as f64]);
+        ((0..n).map(column).collect(), (0..n).map(column).collect())
+    }
+
+    #[test]
+    fn test_batches_are_stacked() {
+        let (inputs, labels) = samples(10);
+        let data = Samples::new(&inputs, &labels).unwrap();
+        let loader = DataLoader::new(&data, 4).unwrap();
+
+        let batches: Vec<(Matrix, Matrix)> = loader.epoch(0).map(|b| b.unwrap()).collect();
+        assert_eq!(loader.len(), 3);
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batches[0].0.data(), &[0.0, 1.0, 2.0, 3.0]);
+        assert_eq!((batches[0].1.rows, batches[0].1.cols), (1, 4));
+        assert_eq!(batches[2].0.data(), &[8.0, 9.0]);
+    }
+
+    #[test]
+    fn test_drop_last() {
+        let (inputs, labels) = samples(10);
+        let data = Samples::new(&inputs, &labels).unwrap();
+        let loader = DataLoader::new(&data, 4).unwrap().drop_last(true);
+
+        assert_eq!(loader.len(), 2);
+        assert!(loader.epoch(0).all(|b| b.unwrap().0.cols == 4));
+    }
+
+    #[test]
+    fn test_shuffle_is_a_seeded_permutation() {
+        let (inputs, labels) = samples(50);
+        let data = Samples::new(&inputs, &labels).unwrap();
+        let loader = DataLoader::new(&data, 8).unwrap().shuffle(7);
+        let order = loader.order(2);
+        let mut sorted = order.clone();
+        sorted.sort();
+
+        assert_eq!(sorted, (0..50).collect::<Vec<_>>());
+        assert_eq!(order, loader.order(2));
+        assert_ne!(order, loader.order(3));
+        assert_eq!(
+            loader.epoch(2).next().unwrap().unwrap().0.data(),
+            order[..8].iter().map(|&i| i as f64).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_skipping_batches() {
+        let (inputs, labels) = samples(10);
+        let data = Samples::new(&inputs, &labels).unwrap();
+        let loader = DataLoader::new(&data, 3).unwrap();
+
+        let mut batches = loader.epoch(0).skip(2);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches.next().unwrap().unwrap().0.data(), &[6.0, 7.0, 8.0]);
+        assert!(matches!(
+            DataLoader::new(&data, 0),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_augmented_batches_are_fresh_every_epoch() {
+        use crate::data::augmentation::{Augmentation, Augmenter};
+        use crate::data::dataset::ImageDataset;
+        use crate::data::definition::Definition;
+        use crate::data::idx_parser::{IdxArray, IdxData};
+
+        let definition = Definition {
+            name: "tiny",
+            channels: 1,
+            height: 2,
+            width: 2,
+            class_names: &["a", "b", "c", "d"],
+            first_label: 0,
+            transposed: false,
+        };
+        let mut data = ImageDataset::new(
+            definition,
+            IdxArray {
+                shape: vec![4, 2, 2],
+                data: IdxData::U8(vec![100; 16]),
+            },
+            IdxArray {
+                shape: vec![4],
+                data: IdxData::U8(vec![0, 1, 2, 3]),
+            },
+        )
+        .unwrap();
+        let noise = Augmentation::Noise {
+            probability: 1.0,
+            std: 0.1,
+        };
+        data.augment(Augmenter::new(2, 2, vec![noise]).unwrap())
+            .unwrap();
+        let loader = DataLoader::new(&data, 2).unwrap().augment(3);
+        let first = |epoch| loader.epoch(epoch).next().unwrap().unwrap().0;
+
+        assert_ne!(first(0).data(), first(1).data());
+        assert_eq!(first(1).data(), first(1).data());
+        // Skipping a batch doesn't change the ones after it
+        let mut batches = loader.epoch(0);
+        batches.next();
+        assert_eq!(
+            loader.epoch(0).nth(1).unwrap().unwrap().0.data(),
+            batches.next().unwrap().unwrap().0.data()
+        );
+        // Evaluation batches stay as they are
+        let plain = DataLoader::new(&data, 2).unwrap();
+        assert_eq!(
+            plain.epoch(0).next().unwrap().unwrap().0.data(),
+            &[100.0 / 255.0; 8]
+        );
+    }
+}
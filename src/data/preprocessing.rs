@@ -0,0 +1,454 @@
+use crate::error::{Error, Result};
+use crate::math::matrix::Matrix;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+// A preprocessing step before it has seen any data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    // Every feature scaled from its [min, max] on the training set to [low, high]
+    MinMax { low: f64, high: f64 },
+    // One mean and standard deviation over every feature, e.g. all pixels of an image
+    ZScore,
+    // Every feature standardised with its own mean and standard deviation
+    PerFeature,
+    // ZCA whitening, decorrelates the features and gives them unit variance. `epsilon` is added to
+    // the covariance eigenvalues to keep near-constant directions from blowing up.
+    Whiten { epsilon: f64 },
+}
+
+// A step fitted on the training set, applied unchanged to any later data.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Transform {
+    MinMax {
+        min: Vec<f64>,
+        max: Vec<f64>,
+        low: f64,
+        high: f64,
+    },
+    ZScore {
+        mean: f64,
+        std: f64,
+    },
+    PerFeature {
+        mean: Vec<f64>,
+        std: Vec<f64>,
+    },
+    Whiten {
+        mean: Vec<f64>,
+        // (features x features), applied to the centred samples
+        matrix: Matrix,
+    },
+}
+
+// Fitted transforms applied in order. Saved next to a model so test and production inputs get
+// exactly the preprocessing the model was trained with.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Pipeline {
+    pub transforms: Vec<Transform>,
+}
+
+impl Pipeline {
+    // Fits every step on `samples`, (features x 1) columns, each on the output of the steps before it.
+    pub fn fit(steps: &[Step], samples: &[Matrix]) -> Result<Pipeline> {
+        Pipeline::fit_batch(steps, &Matrix::from_columns(samples)?)
+    }
+
+    // Same as `fit` for samples already stacked into a (features x n) matrix
+    pub fn fit_batch(steps: &[Step], x: &Matrix) -> Result<Pipeline> {
+        let mut x = x.clone();
+        let mut pipeline = Pipeline::default();
+        for step in steps {
+            let transform = Transform::fit(*step, &x)?;
+            x = transform.apply(&x)?;
+            pipeline.transforms.push(transform);
+        }
+        Ok(pipeline)
+    }
+
+    // Transforms a (features x n) matrix of samples.
+    pub fn apply(&self, x: &Matrix) -> Result<Matrix> {
+        let mut x = x.clone();
+        for transform in &self.transforms {
+            x = transform.apply(&x)?;
+        }
+        Ok(x)
+    }
+
+    // Transforms every (features x 1) sample.
+    pub fn apply_all(&self, samples: &[Matrix]) -> Result<Vec<Matrix>> {
+        samples.iter().map(|s| self.apply(s)).collect()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        Ok(fs::write(path, serde_json::to_vec(self)?)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Pipeline> {
+        let pipeline: Pipeline = serde_json::from_slice(&fs::read(path)?)?;
+        pipeline.check()?;
+        Ok(pipeline)
+    }
+
+    // Every transform must be consistent in itself and take as many features as the one before it
+    // gives, so a malformed file is rejected on load instead of failing mid-training.
+    fn check(&self) -> Result<()> {
+        let mut features = None;
+        for (i, transform) in self.transforms.iter().enumerate() {
+            let consistent = match transform {
+                Transform::MinMax { min, max, .. } => min.len() == max.len(),
+                Transform::ZScore { .. } => true,
+                Transform::PerFeature { mean, std } => mean.len() == std.len(),
+                Transform::Whiten { mean, matrix } => {
+                    (matrix.rows, matrix.cols) == (mean.len(), mean.len())
+                }
+            };
+            if !consistent {
+                return Err(Error::Format(format!(
+                    "Transform {} has parameters of different sizes",
+                    i
+                )));
+            }
+            match (features, transform.features()) {
+                (Some(before), Some(n)) if before != n => {
+                    return Err(Error::Format(format!(
+                        "Transform {} takes {} features but is given {}",
+                        i, n, before
+                    )))
+                }
+                (_, Some(n)) => features = Some(n),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transform {
+    // Fits a step on (features x n) samples
+    pub fn fit(step: Step, x: &Matrix) -> Result<Transform> {
+        if x.cols == 0 {
+            return Err(Error::Shape(
+                "Preprocessing needs at least one sample to fit".to_string(),
+            ));
+        }
+        let transform = match step {
+            Step::MinMax { low, high } => {
+                let min = (0..x.rows).map(|i| row(x, i).fold(f64::INFINITY, f64::min));
+                let max = (0..x.rows).map(|i| row(x, i).fold(f64::NEG_INFINITY, f64::max));
+                Transform::MinMax {
+                    min: min.collect(),
+                    max: max.collect(),
+                    low,
+                    high,
+                }
+            }
+            Step::ZScore => {
+                let (mean, std) = mean_std(x.data().iter().copied());
+                Transform::ZScore { mean, std }
+            }
+            Step::PerFeature => {
+                let (mean, std) = (0..x.rows).map(|i| mean_std(row(x, i))).unzip();
+                Transform::PerFeature { mean, std }
+            }
+            Step::Whiten { epsilon } => {
+                let mean: Vec<f64> = (0..x.rows).map(|i| mean_std(row(x, i)).0).collect();
+                let centred = x - &Matrix::new(x.rows, 1, mean.clone());
+                let mut t = centred.clone();
+                t.transpose();
+                let covariance = &centred.dot(&t)? * &Matrix::new(1, 1, vec![1.0 / x.cols as f64]);
+                Transform::Whiten {
+                    mean,
+                    matrix: zca(&covariance, epsilon),
+                }
+            }
+        };
+        Ok(transform)
+    }
+
+    pub fn features(&self) -> Option<usize> {
+        match self {
+            Transform::MinMax { min, .. } => Some(min.len()),
+            Transform::ZScore { .. } => None,
+            Transform::PerFeature { mean, .. } => Some(mean.len()),
+            Transform::Whiten { mean, .. } => Some(mean.len()),
+        }
+    }
+
+    // Transforms (features x n) samples
+    pub fn apply(&self, x: &Matrix) -> Result<Matrix> {
+        if let Some(features) = self.features() {
+            if x.rows != features {
+                return Err(Error::Shape(format!(
+                    "Samples have {} features but the transform was fitted on {}",
+                    x.rows, features
+                )));
+            }
+        }
+
+        let result = match self {
+            Transform::MinMax {
+                min,
+                max,
+                low,
+                high,
+            } => {
+                // A constant feature maps to `low`
+                let scale: Vec<f64> = min
+                    .iter()
+                    .zip(max)
+                    .map(|(a, b)| if b > a { (high - low) / (b - a) } else { 0.0 })
+                    .collect();
+                let shifted = x - &Matrix::new(x.rows, 1, min.clone());
+                &(&shifted * &Matrix::new(x.rows, 1, scale)) + &Matrix::new(1, 1, vec![*low])
+            }
+            Transform::ZScore { mean, std } => {
+                let std = non_zero(*std);
+                x.map(|v| (v - mean) / std)
+            }
+            Transform::PerFeature { mean, std } => {
+                let inverse: Vec<f64> = std.iter().map(|&s| 1.0 / non_zero(s)).collect();
+                let centred = x - &Matrix::new(x.rows, 1, mean.clone());
+                &centred * &Matrix::new(x.rows, 1, inverse)
+            }
+            Transform::Whiten { mean, matrix } => {
+                let centred = x - &Matrix::new(x.rows, 1, mean.clone());
+                matrix.dot(&centred)?
+            }
+        };
+        Ok(result)
+    }
+}
+
+fn row(x: &Matrix, i: usize) -> impl Iterator<Item = f64> + Clone + '_ {
+    (0..x.cols).map(move |j| x.get(i, j))
+}
+
+// Population mean and standard deviation
+fn mean_std(values: impl Iterator<Item = f64> + Clone) -> (f64, f64) {
+    let n = values.clone().count().max(1) as f64;
+    let mean = values.clone().sum::<f64>() / n;
+    let variance = values.map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+    (mean, variance.sqrt())
+}
+
+// Constant features are only centred, not divided by a zero deviation
+#[inline]
+fn non_zero(std: f64) -> f64 {
+    if std > 1e-12 {
+        std
+    } else {
+        1.0
+    }
+}
+
+// U diag(1 / sqrt(lambda + epsilon)) U^T from the eigendecomposition of the covariance
+fn zca(covariance: &Matrix, epsilon: f64) -> Matrix {
+    let (values, vectors) = symmetric_eigen(covariance);
+    let n = covariance.rows;
+    let mut data = vec![0.0; n * n];
+    for i in 0..n {
+        for j in 0..n {
+            data[i * n + j] = (0..n)
+                .map(|k| vectors[i][k] * vectors[j][k] / (values[k].max(0.0) + epsilon).sqrt())
+                .sum();
+        }
+    }
+    Matrix::new(n, n, data)
+}
+
+// Cyclic Jacobi eigenvalue algorithm for a symmetric matrix. Returns the eigenvalues and the
+// eigenvectors as the columns of `vectors[row][k]`. Cubic in the number of features per sweep.
+fn symmetric_eigen(m: &Matrix) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let n = m.rows;
+    let mut a: Vec<Vec<f64>> = (0..n)
+        .map(|i| (0..n).map(|j| m.get(i, j)).collect())
+        .collect();
+    let mut v: Vec<Vec<f64>> = (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect();
+
+    for _ in 0..100 {
+        let off: f64 = (0..n)
+            .flat_map(|i| (0..n).filter(move |&j| j != i).map(move |j| (i, j)))
+            .map(|(i, j)| a[i][j] * a[i][j])
+            .sum();
+        if off < 1e-22 {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                if a[p][q].abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for row in a.iter_mut().chain(v.iter_mut()) {
+                    let (xp, xq) = (row[p], row[q]);
+                    row[p] = c * xp - s * xq;
+                    row[q] = s * xp + c * xq;
+                }
+                let (top, bottom) = a.split_at_mut(q);
+                for (xp, xq) in top[p].iter_mut().zip(bottom[0].iter_mut()) {
+                    let (yp, yq) = (*xp, *xq);
+                    *xp = c * yp - s * yq;
+                    *xq = s * yp + c * yq;
+                }
+            }
+        }
+    }
+    ((0..n).map(|i| a[i][i]).collect(), v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples() -> Vec<Matrix> {
+        vec![
+            Matrix::new(3, 1, vec![0.0, 10.0, 5.0]),
+            Matrix::new(3, 1, vec![2.0, 30.0, 5.0]),
+            Matrix::new(3, 1, vec![4.0, 20.0, 5.0]),
+            Matrix::new(3, 1, vec![6.0, 60.0, 5.0]),
+        ]
+    }
+
+    fn columns(pipeline: &Pipeline, samples: &[Matrix]) -> Matrix {
+        pipeline
+            .apply(&Matrix::from_columns(samples).unwrap())
+            .unwrap()
+    }
+
+    fn row_stats(x: &Matrix, i: usize) -> (f64, f64) {
+        mean_std(row(x, i))
+    }
+
+    #[test]
+    fn test_min_max() {
+        let pipeline = Pipeline::fit(
+            &[Step::MinMax {
+                low: 0.0,
+                high: 1.0,
+            }],
+            &samples(),
+        )
+        .unwrap();
+        let x = columns(&pipeline, &samples());
+
+        assert_eq!(
+            (0..4).map(|j| x.get(0, j)).collect::<Vec<_>>(),
+            vec![0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0]
+        );
+        assert_eq!(x.get(1, 3), 1.0);
+        // The constant feature
+        assert!((0..4).all(|j| x.get(2, j) == 0.0));
+    }
+
+    #[test]
+    fn test_z_score_uses_one_mean() {
+        let pipeline = Pipeline::fit(&[Step::ZScore], &samples()).unwrap();
+        let x = columns(&pipeline, &samples());
+        let (mean, std) = mean_std(x.data().iter().copied());
+
+        assert!(mean.abs() < 1e-12);
+        assert!((std - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_per_feature() {
+        let pipeline = Pipeline::fit(&[Step::PerFeature], &samples()).unwrap();
+        let x = columns(&pipeline, &samples());
+
+        for i in 0..2 {
+            let (mean, std) = row_stats(&x, i);
+            assert!(mean.abs() < 1e-12);
+            assert!((std - 1.0).abs() < 1e-12);
+        }
+        assert_eq!(row_stats(&x, 2), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_whitening_decorrelates() {
+        let samples: Vec<Matrix> = (0..50)
+            .map(|k| {
+                let a = ((k * 7) % 11) as f64;
+                let b = ((k * 3) % 5) as f64;
+                Matrix::new(3, 1, vec![a, a + b, ((k * 5) % 7) as f64 - a])
+            })
+            .collect();
+        let pipeline = Pipeline::fit(&[Step::Whiten { epsilon: 1e-9 }], &samples).unwrap();
+        let x = columns(&pipeline, &samples);
+
+        let mut t = x.clone();
+        t.transpose();
+        let covariance = x.dot(&t).unwrap();
+        for i in 0..3 {
+            for j in 0..3 {
+                let expected = if i == j { 50.0 } else { 0.0 };
+                assert!((covariance.get(i, j) - expected).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fitted_pipeline_reapplies_after_saving() {
+        let steps = [
+            Step::MinMax {
+                low: -1.0,
+                high: 1.0,
+            },
+            Step::PerFeature,
+        ];
+        let pipeline = Pipeline::fit(&steps, &samples()).unwrap();
+        let path = std::env::temp_dir().join("nn_scratch_pipeline.json");
+        pipeline.save(&path).unwrap();
+        let loaded = Pipeline::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let test = vec![Matrix::new(3, 1, vec![3.0, 45.0, 7.0])];
+        assert_eq!(loaded.transforms.len(), 2);
+        assert_eq!(
+            loaded.apply_all(&test).unwrap()[0].data(),
+            pipeline.apply_all(&test).unwrap()[0].data()
+        );
+    }
+
+    #[test]
+    fn test_feature_count_mismatch() {
+        let pipeline = Pipeline::fit(&[Step::PerFeature], &samples()).unwrap();
+        let wrong = Matrix::new(2, 1, vec![1.0, 2.0]);
+        assert!(matches!(pipeline.apply(&wrong), Err(Error::Shape(_))));
+    }
+
+    #[test]
+    fn test_load_rejects_corrupted_pipeline() {
+        let path = std::env::temp_dir().join("nn_scratch_corrupted_pipeline.json");
+        let steps = [Step::PerFeature, Step::Whiten { epsilon: 1e-3 }];
+        Pipeline::fit(&steps, &samples())
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        let original: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+
+        let corruptions: [fn(&mut serde_json::Value); 4] = [
+            |p| p["transforms"][0]["PerFeature"]["std"] = serde_json::json!([1.0]),
+            |p| p["transforms"][1]["Whiten"]["mean"] = serde_json::json!([0.0, 0.0]),
+            |p| p["transforms"][1]["Whiten"]["matrix"]["row_stride"] = 50.into(),
+            |p| {
+                let min_max = serde_json::json!({"MinMax": {"min": [0.0], "max": [1.0], "low": 0.0, "high": 1.0}});
+                p["transforms"].as_array_mut().unwrap().push(min_max);
+            },
+        ];
+        for corrupt in corruptions {
+            let mut json = original.clone();
+            corrupt(&mut json);
+            std::fs::write(&path, json.to_string()).unwrap();
+            assert!(matches!(Pipeline::load(&path), Err(Error::Format(_))));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
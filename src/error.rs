@@ -0,0 +1,59 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+
+// Every way the public APIs of the crate can fail. Misuse and bad input are reported here rather
+// than by panicking, so an embedding service can reject a request and carry on.
+#[derive(Debug)]
+pub enum Error {
+    // Reading or writing a file failed
+    Io(io::Error),
+    // A file or serialised value is malformed, truncated or of an unsupported version
+    Format(String),
+    // Matrix, sample or label dimensions don't fit together
+    Shape(String),
+    // An operation was called when the network or trainer wasn't ready for it
+    State(String),
+    // A hyperparameter or setting is out of its valid range
+    InvalidArgument(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Format(msg) => write!(f, "Format error: {}", msg),
+            Error::Shape(msg) => write!(f, "Shape error: {}", msg),
+            Error::State(msg) => write!(f, "State error: {}", msg),
+            Error::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+// JSON is only used for model, checkpoint and report files, so its errors are format errors unless
+// the underlying reader or writer failed.
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        if e.is_io() {
+            Error::Io(e.into())
+        } else {
+            Error::Format(e.to_string())
+        }
+    }
+}
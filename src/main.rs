@@ -1,43 +1,96 @@
 mod data;
+mod error;
 mod math;
 mod nn;
 
-use crate::data::idx_parser;
-use crate::math::activation::{d_sigmoid, sigmoid, softmax};
-use crate::math::loss_functions::cross_entropy;
+use crate::data::augmentation::{Augmentation, Augmenter};
+use crate::data::dataset::{Dataset, ImageDataset};
+use crate::data::definition::MNIST;
+use crate::data::preprocessing::{Pipeline, Step};
+use crate::math::activation::Activation;
+use crate::math::loss_functions::CrossEntropy;
+use crate::nn::checkpoint;
+use crate::nn::evaluation;
+use crate::nn::initializer::Initializer;
+use crate::nn::optimizer::Adam;
 use crate::nn::perceptron;
+use crate::nn::trainer::{TrainConfig, Trainer};
 
-fn main() {
-    let mut train_data = idx_parser::parse(
+const CHECKPOINT_DIR: &str = "./checkpoints";
+const PREPROCESSING_PATH: &str = "./mnist_preprocessing.json";
+const SEED: u64 = 42;
+
+fn main() -> error::Result<()> {
+    let mut train = ImageDataset::load_idx(
+        MNIST,
         "./mnist_data/train-images.idx3-ubyte",
-    );
-    let train_labels = idx_parser::parse(
         "./mnist_data/train-labels.idx1-ubyte",
-    );
-
-    let mut nn = perceptron::Network::new(0.001, cross_entropy);
-    nn.add_inp_layer(256, 784, sigmoid, Some(d_sigmoid));
-    nn.add_layer(64, sigmoid, Some(d_sigmoid));
-    nn.add_layer(10, softmax, None);
-
-    let epoch = 50;
-    let batch_size = train_data.len() / epoch;
+    )?;
+    let mut test = ImageDataset::load_idx(
+        MNIST,
+        "./mnist_data/t10k-images.idx3-ubyte",
+        "./mnist_data/t10k-labels.idx1-ubyte",
+    )?;
 
-    for e in 0..epoch {
-        let train_batch_data = &mut train_data[e * batch_size..(e * batch_size + batch_size)];
-        let train_batch_labels = &train_labels[e * batch_size..(e * batch_size + batch_size)];
+    // Standardise with statistics of the training set only, and reuse the stored fit so a resumed
+    // run sees the same inputs. Every 6th image is plenty to estimate them without converting the
+    // whole set at once.
+    let pipeline = if std::path::Path::new(PREPROCESSING_PATH).exists() {
+        Pipeline::load(PREPROCESSING_PATH)?
+    } else {
+        let sample: Vec<usize> = (0..train.len()).step_by(6).collect();
+        let pipeline = Pipeline::fit_batch(&[Step::ZScore], &train.images(&sample))?;
+        pipeline.save(PREPROCESSING_PATH)?;
+        pipeline
+    };
+    train.transform(pipeline.clone())?;
+    train.augment(Augmenter::mnist(vec![
+        Augmentation::Translate {
+            probability: 0.5,
+            pixels: 2.0,
+        },
+        Augmentation::Rotate {
+            probability: 0.5,
+            degrees: 10.0,
+        },
+        Augmentation::Zoom {
+            probability: 0.3,
+            range: 0.1,
+        },
+    ])?)?;
+    test.transform(pipeline)?;
 
-        let mut training_loss = 0.0;
+    let trainer = Trainer::new(TrainConfig {
+        epochs: 10,
+        batch_size: 64,
+        validation_split: 0.1,
+        seed: SEED,
+        checkpoint_dir: Some(CHECKPOINT_DIR.into()),
+        verbose: true,
+        ..TrainConfig::default()
+    })?;
 
-        for (i, x) in train_batch_data.iter_mut().enumerate() {
-            let y = &train_batch_labels[i];
-            training_loss += nn.feed_forward(x, y);
-            nn.calc_gradients(x, y);
-        }
+    let nn = if checkpoint::exists(CHECKPOINT_DIR) {
+        println!("Resuming from {}", CHECKPOINT_DIR);
+        trainer.resume_from(&train)?.0
+    } else {
+        let mut nn = perceptron::Network::new(Adam::new(0.001), CrossEntropy);
+        nn.set_seed(SEED);
+        let definition = train.definition();
+        let (inputs, classes) = (definition.image_size(), definition.classes());
+        nn.add_inp_layer(256, inputs, Activation::Sigmoid, Initializer::XavierUniform)?;
+        nn.add_layer(64, Activation::Sigmoid, Initializer::XavierUniform)?;
+        nn.add_layer(classes, Activation::Softmax, Initializer::XavierUniform)?;
+        // Saved with every checkpoint and the final model
+        nn.set_labels(train.encoder().clone())?;
+        trainer.fit(&mut nn, &train)?;
+        nn
+    };
 
-        training_loss /= batch_size as f64;
-        println!("Epoch: {} Train Loss: {}", e, training_loss);
+    nn.save("./mnist_model.bin")?;
 
-        nn.update_gradients(batch_size);
-    }
+    let report = evaluation::evaluate(&nn, &test, 5)?;
+    println!("{}", report);
+    std::fs::write("./mnist_evaluation.json", report.to_json())?;
+    Ok(())
 }
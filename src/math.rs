@@ -2,3 +2,4 @@
 pub mod activation;
 pub mod loss_functions;
 pub mod matrix;
+pub mod random;
@@ -1,4 +1,66 @@
-use crate::math::matrix::Matrix;
+use crate::math::matrix::{Axis, Matrix};
+use serde::{Deserialize, Serialize};
+
+// Pairs every activation with its derivative so a layer can't mix them up, parameterised variants carry
+// their parameters so they are saved along with the model.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Activation {
+    Identity,
+    Relu,
+    LeakyRelu { alpha: f64 },
+    Elu { alpha: f64 },
+    Selu,
+    Gelu,
+    Silu,
+    Softplus,
+    Mish,
+    Tanh,
+    Sigmoid,
+    HardSigmoid,
+    Softmax,
+    LogSoftmax,
+}
+
+impl Activation {
+    pub fn forward(&self, m: &Matrix) -> Matrix {
+        match *self {
+            Activation::Identity => m.clone(),
+            Activation::Relu => relu(m),
+            Activation::LeakyRelu { alpha } => leaky_relu(m, alpha),
+            Activation::Elu { alpha } => elu(m, alpha),
+            Activation::Selu => selu(m),
+            Activation::Gelu => gelu(m),
+            Activation::Silu => silu(m),
+            Activation::Softplus => softplus(m),
+            Activation::Mish => mish(m),
+            Activation::Tanh => tanh(m),
+            Activation::Sigmoid => sigmoid(m),
+            Activation::HardSigmoid => hard_sigmoid(m),
+            Activation::Softmax => softmax(m),
+            Activation::LogSoftmax => log_softmax(m),
+        }
+    }
+
+    // Chains `grad`, the gradient with respect to this activation's output, back to its input `z`.
+    pub fn backward(&self, z: &Matrix, grad: &Matrix) -> Matrix {
+        match *self {
+            Activation::Identity => grad.clone(),
+            Activation::Relu => grad * &d_relu(z),
+            Activation::LeakyRelu { alpha } => grad * &d_leaky_relu(z, alpha),
+            Activation::Elu { alpha } => grad * &d_elu(z, alpha),
+            Activation::Selu => grad * &d_selu(z),
+            Activation::Gelu => grad * &d_gelu(z),
+            Activation::Silu => grad * &d_silu(z),
+            Activation::Softplus => grad * &d_softplus(z),
+            Activation::Mish => grad * &d_mish(z),
+            Activation::Tanh => grad * &d_tanh(z),
+            Activation::Sigmoid => grad * &d_sigmoid(z),
+            Activation::HardSigmoid => grad * &d_hard_sigmoid(z),
+            Activation::Softmax => softmax_backward(&softmax(z), grad),
+            Activation::LogSoftmax => log_softmax_backward(&softmax(z), grad),
+        }
+    }
+}
 
 pub fn relu(m: &Matrix) -> Matrix {
     let mut data = vec![0_f64; m.data().len()];
@@ -23,6 +85,95 @@ pub fn d_relu(m: &Matrix) -> Matrix {
     Matrix::new(m.rows, m.cols, data)
 }
 
+pub fn leaky_relu(m: &Matrix, alpha: f64) -> Matrix {
+    m.map(|x| if x > 0.0 { x } else { alpha * x })
+}
+
+pub fn d_leaky_relu(m: &Matrix, alpha: f64) -> Matrix {
+    m.map(|x| if x > 0.0 { 1.0 } else { alpha })
+}
+
+pub fn elu(m: &Matrix, alpha: f64) -> Matrix {
+    m.map(|x| if x > 0.0 { x } else { alpha * x.exp_m1() })
+}
+
+pub fn d_elu(m: &Matrix, alpha: f64) -> Matrix {
+    m.map(|x| if x > 0.0 { 1.0 } else { alpha * x.exp() })
+}
+
+// Self-normalising constants from Klambauer et al. 2017
+const SELU_LAMBDA: f64 = 1.050_700_987_355_480_5;
+const SELU_ALPHA: f64 = 1.673_263_242_354_377_3;
+
+pub fn selu(m: &Matrix) -> Matrix {
+    &elu(m, SELU_ALPHA) * &Matrix::new(1, 1, vec![SELU_LAMBDA])
+}
+
+pub fn d_selu(m: &Matrix) -> Matrix {
+    &d_elu(m, SELU_ALPHA) * &Matrix::new(1, 1, vec![SELU_LAMBDA])
+}
+
+// GELU using the tanh approximation: 0.5 * x * (1 + tanh(sqrt(2 / pi) * (x + 0.044715 * x^3)))
+const GELU_C: f64 = 0.797_884_560_802_865_4; // sqrt(2 / pi)
+
+pub fn gelu(m: &Matrix) -> Matrix {
+    m.map(|x| 0.5 * x * (1.0 + (GELU_C * (x + 0.044715 * x.powi(3))).tanh()))
+}
+
+pub fn d_gelu(m: &Matrix) -> Matrix {
+    m.map(|x| {
+        let t = (GELU_C * (x + 0.044715 * x.powi(3))).tanh();
+        0.5 * (1.0 + t) + 0.5 * x * (1.0 - t * t) * GELU_C * (1.0 + 3.0 * 0.044715 * x * x)
+    })
+}
+
+// SiLU / swish: x * sigmoid(x)
+pub fn silu(m: &Matrix) -> Matrix {
+    m.map(|x| x / (1.0 + (-x).exp()))
+}
+
+pub fn d_silu(m: &Matrix) -> Matrix {
+    m.map(|x| {
+        let s = 1.0 / (1.0 + (-x).exp());
+        s * (1.0 + x * (1.0 - s))
+    })
+}
+
+// ln(1 + e^x), written as max(x, 0) + ln(1 + e^-|x|) so large inputs don't overflow
+#[inline]
+fn softplus_scalar(x: f64) -> f64 {
+    x.max(0.0) + (-x.abs()).exp().ln_1p()
+}
+
+pub fn softplus(m: &Matrix) -> Matrix {
+    m.map(softplus_scalar)
+}
+
+pub fn d_softplus(m: &Matrix) -> Matrix {
+    sigmoid(m)
+}
+
+// Mish: x * tanh(softplus(x))
+pub fn mish(m: &Matrix) -> Matrix {
+    m.map(|x| x * softplus_scalar(x).tanh())
+}
+
+pub fn d_mish(m: &Matrix) -> Matrix {
+    m.map(|x| {
+        let t = softplus_scalar(x).tanh();
+        let s = 1.0 / (1.0 + (-x).exp());
+        t + x * (1.0 - t * t) * s
+    })
+}
+
+pub fn tanh(m: &Matrix) -> Matrix {
+    m.map(f64::tanh)
+}
+
+pub fn d_tanh(m: &Matrix) -> Matrix {
+    m.map(|x| 1.0 - x.tanh().powi(2))
+}
+
 pub fn sigmoid(m: &Matrix) -> Matrix {
     let mut data = vec![0_f64; m.data().len()];
 
@@ -40,22 +191,87 @@ pub fn d_sigmoid(m: &Matrix) -> Matrix {
     &s * &r
 }
 
-// Softmax is almost always paired with cross-entropy loss function to prevent vanishing gradient and also allows calculation of gradient in simpler way.
-// y_hat - y is the gradient so, no need to explicitly calculate derivative of softmax.
+// Piecewise linear sigmoid: clamp(x / 6 + 0.5, 0, 1)
+pub fn hard_sigmoid(m: &Matrix) -> Matrix {
+    m.map(|x| (x / 6.0 + 0.5).clamp(0.0, 1.0))
+}
+
+pub fn d_hard_sigmoid(m: &Matrix) -> Matrix {
+    m.map(|x| if x > -3.0 && x < 3.0 { 1.0 / 6.0 } else { 0.0 })
+}
+
+// Softmax is almost always paired with cross-entropy, where the gradient with respect to its input collapses
+// to y_hat - y. The network uses that fused form from `Loss::softmax_gradient` when the loss has one and
+// chains the loss gradient through `softmax_backward` otherwise.
+// Every column is one sample, so each column is normalised into its own distribution.
 
 pub fn softmax(m: &Matrix) -> Matrix {
-    let mut data = vec![0_f64; m.data().len()];
-    let sum = m
-        .data()
-        .iter()
-        .map(|x| x.exp())
-        .reduce(|acc, x| acc + x)
-        .unwrap_or(0.0);
-    for i in 0..m.data().len() {
-        data[i] = m.data()[i].exp() / sum;
+    softmax_axis(m, Axis::Column)
+}
+
+// The max is subtracted before exponentiating, which leaves the result unchanged but keeps exp from overflowing.
+pub fn softmax_axis(m: &Matrix, axis: Axis) -> Matrix {
+    m.map_axis(axis, |v, out| {
+        let max = v.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let mut sum = 0.0;
+        for (o, &x) in out.iter_mut().zip(v) {
+            *o = (x - max).exp();
+            sum += *o;
+        }
+        for o in out.iter_mut() {
+            *o /= sum;
+        }
+    })
+}
+
+pub fn log_softmax(m: &Matrix) -> Matrix {
+    log_softmax_axis(m, Axis::Column)
+}
+
+// x - log(sum(exp(x))), computed directly rather than as ln(softmax) so tiny probabilities don't become -inf.
+pub fn log_softmax_axis(m: &Matrix, axis: Axis) -> Matrix {
+    m.map_axis(axis, |v, out| {
+        let lse = log_sum_exp(v);
+        for (o, &x) in out.iter_mut().zip(v) {
+            *o = x - lse;
+        }
+    })
+}
+
+// log(sum(exp(v))) shifted by the max so it stays finite for large values.
+pub fn log_sum_exp(v: &[f64]) -> f64 {
+    let max = v.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if max == f64::NEG_INFINITY {
+        return max;
     }
+    max + v.iter().map(|x| (x - max).exp()).sum::<f64>().ln()
+}
 
-    Matrix::new(m.rows, m.cols, data)
+// Softmax derivative is a Jacobian rather than element-wise, so instead of a `d_softmax` the upstream
+// gradient is chained through it directly: s * (grad - sum(grad * s)) for every column.
+pub fn softmax_backward(s: &Matrix, grad: &Matrix) -> Matrix {
+    let mut data = vec![0_f64; s.data().len()];
+    for j in 0..s.cols {
+        let dot: f64 = (0..s.rows).map(|i| s.get(i, j) * grad.get(i, j)).sum();
+        for i in 0..s.rows {
+            data[i * s.cols + j] = s.get(i, j) * (grad.get(i, j) - dot);
+        }
+    }
+
+    Matrix::new(s.rows, s.cols, data)
+}
+
+// Jacobian of log-softmax chained with `grad` for every column: grad - s * sum(grad), `s` being softmax(z).
+pub fn log_softmax_backward(s: &Matrix, grad: &Matrix) -> Matrix {
+    let mut data = vec![0_f64; s.data().len()];
+    for j in 0..s.cols {
+        let sum: f64 = (0..s.rows).map(|i| grad.get(i, j)).sum();
+        for i in 0..s.rows {
+            data[i * s.cols + j] = grad.get(i, j) - s.get(i, j) * sum;
+        }
+    }
+
+    Matrix::new(s.rows, s.cols, data)
 }
 
 #[cfg(test)]
@@ -100,7 +316,7 @@ mod tests {
         let m = Matrix::new(1, 1, vec![0.0]);
         let s = sigmoid(&m);
         let expected = vec![0.5];
-        assert_vec_approx_eq(&s.data(), &expected);
+        assert_vec_approx_eq(s.data(), &expected);
     }
 
     #[test]
@@ -113,19 +329,19 @@ mod tests {
             0.9525741268224334, // f(3.0)
             0.9820137900379085, // f(4.0)
         ];
-        assert_vec_approx_eq(&s.data(), &expected);
+        assert_vec_approx_eq(s.data(), &expected);
     }
 
     #[test]
     fn test_sigmoid_negative_values() {
-        let mut m = Matrix::new(1, 3, vec![-1.0, -2.0, -3.0]);
-        let s = sigmoid(&mut m);
+        let m = Matrix::new(1, 3, vec![-1.0, -2.0, -3.0]);
+        let s = sigmoid(&m);
         let expected = vec![
             0.2689414213699951,  // f(-1.0)
             0.11920292202211755, // f(-2.0)
             0.04742587317756678, // f(-3.0)
         ];
-        assert_vec_approx_eq(&s.data(), &expected);
+        assert_vec_approx_eq(s.data(), &expected);
     }
 
     #[test]
@@ -137,19 +353,19 @@ mod tests {
             0.5,                 // f(0.0)
             0.07585818002124355, // f(-2.5)
         ];
-        assert_vec_approx_eq(&s.data(), &expected);
+        assert_vec_approx_eq(s.data(), &expected);
     }
 
     #[test]
     fn test_sigmoid_saturation_large_values() {
-        let mut m = Matrix::new(1, 2, vec![20.0, -20.0]);
-        let s = sigmoid(&mut m);
+        let m = Matrix::new(1, 2, vec![20.0, -20.0]);
+        let s = sigmoid(&m);
 
         let expected_pos = 0.9999999979388463;
         let expected_neg = 2.0611536224385576e-9;
 
         let expected = vec![expected_pos, expected_neg];
-        assert_vec_approx_eq(&s.data(), &expected);
+        assert_vec_approx_eq(s.data(), &expected);
 
         assert!(s.data()[0] > 0.99999999);
         assert!(s.data()[1] < 1e-8);
@@ -163,4 +379,200 @@ mod tests {
 
         assert_eq!(s.data(), expected);
     }
+
+    #[test]
+    fn test_softmax_column_sums_to_one() {
+        let m = Matrix::new(3, 1, vec![1.0, 2.0, 3.0]);
+        let s = softmax(&m);
+        let expected = vec![0.09003057317038046, 0.24472847105479764, 0.6652409557748219];
+        assert_vec_approx_eq(s.data(), &expected);
+    }
+
+    #[test]
+    fn test_softmax_per_column() {
+        // Two samples, each column must be its own distribution
+        let m = Matrix::new(2, 2, vec![0.0, 5.0, 0.0, 5.0]);
+        let s = softmax(&m);
+        assert_vec_approx_eq(s.data(), &[0.5, 0.5, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_softmax_backward_matches_numeric() {
+        // Gradient of sum(w * softmax(x)) with respect to x
+        let x = Matrix::new(3, 1, vec![0.5, -1.0, 2.0]);
+        let w = Matrix::new(3, 1, vec![1.0, 2.0, 3.0]);
+        let analytic = softmax_backward(&softmax(&x), &w);
+
+        let h = 1e-6;
+        let f = |v: Vec<f64>| {
+            let s = softmax(&Matrix::new(3, 1, v));
+            (0..3).map(|i| s.data()[i] * w.data()[i]).sum::<f64>()
+        };
+        for i in 0..3 {
+            let mut plus = x.data().to_vec();
+            let mut minus = x.data().to_vec();
+            plus[i] += h;
+            minus[i] -= h;
+            let numeric = (f(plus) - f(minus)) / (2.0 * h);
+            assert!((analytic.data()[i] - numeric).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_leaky_relu() {
+        let m = Matrix::new(1, 3, vec![2.0, 0.0, -2.0]);
+        assert_vec_approx_eq(leaky_relu(&m, 0.1).data(), &[2.0, 0.0, -0.2]);
+        assert_vec_approx_eq(d_leaky_relu(&m, 0.1).data(), &[1.0, 0.1, 0.1]);
+    }
+
+    #[test]
+    fn test_activation_backward_matches_numeric() {
+        // Points are kept away from the kinks of relu-like activations
+        let z = Matrix::new(2, 2, vec![0.7, -1.3, 2.1, -0.4]);
+        let grad = Matrix::new(2, 2, vec![1.0, -0.5, 0.25, 2.0]);
+        let activations = [
+            Activation::Identity,
+            Activation::Relu,
+            Activation::LeakyRelu { alpha: 0.01 },
+            Activation::Elu { alpha: 1.0 },
+            Activation::Selu,
+            Activation::Gelu,
+            Activation::Silu,
+            Activation::Softplus,
+            Activation::Mish,
+            Activation::Tanh,
+            Activation::Sigmoid,
+            Activation::HardSigmoid,
+            Activation::Softmax,
+            Activation::LogSoftmax,
+        ];
+
+        let h = 1e-6;
+        for activation in activations {
+            let analytic = activation.backward(&z, &grad);
+            let f = |v: Vec<f64>| {
+                let out = activation.forward(&Matrix::new(2, 2, v));
+                (0..4).map(|i| out.data()[i] * grad.data()[i]).sum::<f64>()
+            };
+            for i in 0..4 {
+                let mut plus = z.data().to_vec();
+                let mut minus = z.data().to_vec();
+                plus[i] += h;
+                minus[i] -= h;
+                let numeric = (f(plus) - f(minus)) / (2.0 * h);
+                assert!(
+                    (analytic.data()[i] - numeric).abs() < 1e-6,
+                    "{:?} differs at index {}",
+                    activation,
+                    i
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_activation_serde_round_trip() {
+        let activation = Activation::LeakyRelu { alpha: 0.2 };
+        let json = serde_json::to_string(&activation).unwrap();
+        let decoded: Activation = serde_json::from_str(&json).unwrap();
+        assert_eq!(activation, decoded);
+    }
+
+    #[test]
+    fn test_tanh() {
+        let m = Matrix::new(1, 3, vec![-1.0, 0.0, 1.0]);
+        let expected = vec![-0.7615941559557649, 0.0, 0.7615941559557649];
+        assert_vec_approx_eq(tanh(&m).data(), &expected);
+        assert_vec_approx_eq(
+            d_tanh(&m).data(),
+            &[0.41997434161402614, 1.0, 0.41997434161402614],
+        );
+    }
+
+    #[test]
+    fn test_elu() {
+        let m = Matrix::new(1, 3, vec![-1.0, 0.0, 2.0]);
+        assert_vec_approx_eq(elu(&m, 1.0).data(), &[-0.6321205588285577, 0.0, 2.0]);
+        assert_vec_approx_eq(d_elu(&m, 1.0).data(), &[0.36787944117144233, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_selu() {
+        let m = Matrix::new(1, 2, vec![-1.0, 1.0]);
+        let expected = vec![-1.1113307378125625, 1.0507009873554805];
+        assert_vec_approx_eq(selu(&m).data(), &expected);
+    }
+
+    #[test]
+    fn test_gelu() {
+        let m = Matrix::new(1, 3, vec![-1.0, 0.0, 1.0]);
+        let expected = vec![-0.15880800939172324, 0.0, 0.8411919906082768];
+        assert_vec_approx_eq(gelu(&m).data(), &expected);
+        assert_vec_approx_eq(&d_gelu(&m).data()[1..2], &[0.5]);
+    }
+
+    #[test]
+    fn test_silu() {
+        let m = Matrix::new(1, 3, vec![-1.0, 0.0, 1.0]);
+        let expected = vec![-0.2689414213699951, 0.0, 0.7310585786300049];
+        assert_vec_approx_eq(silu(&m).data(), &expected);
+        assert_vec_approx_eq(&d_silu(&m).data()[1..2], &[0.5]);
+    }
+
+    #[test]
+    fn test_softplus_large_values() {
+        let m = Matrix::new(1, 3, vec![-1000.0, 0.0, 1000.0]);
+        let s = softplus(&m);
+        assert_vec_approx_eq(s.data(), &[0.0, std::f64::consts::LN_2, 1000.0]);
+    }
+
+    #[test]
+    fn test_mish() {
+        let m = Matrix::new(1, 3, vec![-1.0, 0.0, 1.0]);
+        let expected = vec![-0.30340146137410895, 0.0, 0.8650983882673103];
+        assert_vec_approx_eq(mish(&m).data(), &expected);
+    }
+
+    #[test]
+    fn test_hard_sigmoid() {
+        let m = Matrix::new(1, 4, vec![-4.0, 0.0, 1.5, 4.0]);
+        assert_vec_approx_eq(hard_sigmoid(&m).data(), &[0.0, 0.5, 0.75, 1.0]);
+        assert_vec_approx_eq(d_hard_sigmoid(&m).data(), &[0.0, 1.0 / 6.0, 1.0 / 6.0, 0.0]);
+    }
+
+    #[test]
+    fn test_softmax_large_logits() {
+        let m = Matrix::new(3, 1, vec![1000.0, 1001.0, 1002.0]);
+        let s = softmax(&m);
+        let expected = vec![0.09003057317038046, 0.24472847105479764, 0.6652409557748219];
+        assert_vec_approx_eq(s.data(), &expected);
+    }
+
+    #[test]
+    fn test_softmax_rows() {
+        let m = Matrix::new(2, 2, vec![0.0, 0.0, 1.0, 1.0]);
+        let s = softmax_axis(&m, Axis::Row);
+        assert_vec_approx_eq(s.data(), &[0.5, 0.5, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_log_softmax_matches_ln_softmax() {
+        let m = Matrix::new(3, 2, vec![1.0, -2.0, 2.0, 0.5, 3.0, 4.0]);
+        let expected = softmax(&m).map(f64::ln);
+        assert_vec_approx_eq(log_softmax(&m).data(), expected.data());
+    }
+
+    #[test]
+    fn test_log_softmax_stays_finite() {
+        // softmax underflows to 0 for the first entry, log-softmax must not become -inf
+        let m = Matrix::new(2, 1, vec![-1000.0, 1000.0]);
+        let l = log_softmax(&m);
+        assert_vec_approx_eq(l.data(), &[-2000.0, 0.0]);
+    }
+
+    #[test]
+    fn test_log_sum_exp() {
+        assert!((log_sum_exp(&[1000.0, 1000.0]) - (1000.0 + std::f64::consts::LN_2)).abs() < 1e-9);
+        assert_eq!(log_sum_exp(&[]), f64::NEG_INFINITY);
+    }
 }
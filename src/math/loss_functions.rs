@@ -1,46 +1,875 @@
+use crate::error::{Error, Result};
+use crate::math::activation::{log_softmax, softmax};
 use crate::math::matrix::Matrix;
+use serde::{Deserialize, Serialize};
 
-pub fn sse(prediction: &Matrix, actual: &Matrix) -> f64 {
-    if actual.rows != prediction.rows {
-        panic!("Dimensions do not match");
+// Every column of `prediction` and `actual` is one sample, losses are summed per sample and averaged over the batch.
+
+pub trait Loss: Send + Sync {
+    // Loss averaged over the batch.
+    fn value(&self, prediction: &Matrix, actual: &Matrix) -> f64;
+
+    // Derivative of every sample's loss with respect to its prediction, same shape as `prediction`.
+    // Not averaged over the batch, the network does that when it applies the update.
+    fn gradient(&self, prediction: &Matrix, actual: &Matrix) -> Matrix;
+
+    // Both of the above, losses that share work between the two override this to do it in one pass.
+    fn value_and_gradient(&self, prediction: &Matrix, actual: &Matrix) -> (f64, Matrix) {
+        (
+            self.value(prediction, actual),
+            self.gradient(prediction, actual),
+        )
     }
 
-    let mut loss = 0.0;
-    for i in 0..actual.rows {
-        loss += (prediction.data()[i] - actual.data()[i]).powi(2);
+    // Gradient with respect to the softmax input when `prediction` is a softmax output.
+    // Losses with a simpler fused form override this, others get chained through the softmax Jacobian.
+    fn softmax_gradient(&self, _prediction: &Matrix, _actual: &Matrix) -> Option<Matrix> {
+        None
+    }
+
+    // Checks that `actual` holds labels the loss can compare with `prediction`, so they can be rejected
+    // up front instead of `value` and `gradient` panicking on them. The same shape by default.
+    fn check_labels(&self, prediction: &Matrix, actual: &Matrix) -> Result<()> {
+        dimensions_match(prediction, actual)
+    }
+
+    // Whether the loss expects raw logits, in which case predictions are turned into probabilities with softmax.
+    fn takes_logits(&self) -> bool {
+        false
     }
 
-    loss
+    // Serialisable copy of the loss so it can be saved with a model, `None` for losses that can't be.
+    fn to_saved(&self) -> Option<SavedLoss> {
+        None
+    }
 }
 
-// Suitable for multi-class classification
-pub fn cross_entropy(prediction: &Matrix, actual: &Matrix) -> f64 {
-    if actual.rows != prediction.rows {
-        panic!("Dimensions do not match");
+// Every built-in loss with its parameters, as stored in saved models.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SavedLoss {
+    Sse(Sse),
+    CrossEntropy(CrossEntropy),
+    BinaryCrossEntropy(BinaryCrossEntropy),
+    SoftmaxCrossEntropy(SoftmaxCrossEntropy),
+    Mse(Mse),
+    Mae(Mae),
+    Huber(Huber),
+    Hinge(Hinge),
+    KlDivergence(KlDivergence),
+    Focal(Focal),
+    Nll(Nll),
+}
+
+impl SavedLoss {
+    pub fn restore(self) -> Box<dyn Loss> {
+        match self {
+            SavedLoss::Sse(l) => Box::new(l),
+            SavedLoss::CrossEntropy(l) => Box::new(l),
+            SavedLoss::BinaryCrossEntropy(l) => Box::new(l),
+            SavedLoss::SoftmaxCrossEntropy(l) => Box::new(l),
+            SavedLoss::Mse(l) => Box::new(l),
+            SavedLoss::Mae(l) => Box::new(l),
+            SavedLoss::Huber(l) => Box::new(l),
+            SavedLoss::Hinge(l) => Box::new(l),
+            SavedLoss::KlDivergence(l) => Box::new(l),
+            SavedLoss::Focal(l) => Box::new(l),
+            SavedLoss::Nll(l) => Box::new(l),
+        }
     }
+}
 
-    let mut loss = 0.0;
-    for i in 0..actual.rows {
-        let p = prediction.data()[i];
-        let y = actual.data()[i];
-        loss += y * p.ln();
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sse;
+
+impl Loss for Sse {
+    fn value(&self, prediction: &Matrix, actual: &Matrix) -> f64 {
+        sse(prediction, actual)
+    }
+
+    fn gradient(&self, prediction: &Matrix, actual: &Matrix) -> Matrix {
+        d_sse(prediction, actual)
+    }
+
+    fn to_saved(&self) -> Option<SavedLoss> {
+        Some(SavedLoss::Sse(self.clone()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CrossEntropy;
+
+impl Loss for CrossEntropy {
+    fn value(&self, prediction: &Matrix, actual: &Matrix) -> f64 {
+        cross_entropy(prediction, actual)
+    }
+
+    fn gradient(&self, prediction: &Matrix, actual: &Matrix) -> Matrix {
+        d_cross_entropy(prediction, actual)
+    }
+
+    // Softmax followed by cross-entropy collapses to y_hat - y
+    fn softmax_gradient(&self, prediction: &Matrix, actual: &Matrix) -> Option<Matrix> {
+        check_dimensions(prediction, actual);
+        Some(prediction - actual)
+    }
+
+    fn to_saved(&self) -> Option<SavedLoss> {
+        Some(SavedLoss::CrossEntropy(self.clone()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BinaryCrossEntropy;
+
+impl Loss for BinaryCrossEntropy {
+    fn value(&self, prediction: &Matrix, actual: &Matrix) -> f64 {
+        binary_cross_entropy(prediction, actual)
+    }
+
+    fn gradient(&self, prediction: &Matrix, actual: &Matrix) -> Matrix {
+        d_binary_cross_entropy(prediction, actual)
+    }
+
+    fn to_saved(&self) -> Option<SavedLoss> {
+        Some(SavedLoss::BinaryCrossEntropy(self.clone()))
+    }
+}
+
+// Cross-entropy computed straight from the logits of an `Activation::Identity` output layer.
+// Softmax is folded in through log-sum-exp, so there is no ln(0) and the gradient is just softmax(z) - y.
+// Targets can be smoothed towards uniform and every class can be given a weight, for imbalanced data.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct SoftmaxCrossEntropy {
+    pub label_smoothing: f64,
+    pub class_weights: Option<Vec<f64>>,
+}
+
+impl SoftmaxCrossEntropy {
+    pub fn new() -> Self {
+        SoftmaxCrossEntropy::default()
+    }
+
+    pub fn with_label_smoothing(mut self, label_smoothing: f64) -> Result<Self> {
+        if !(0.0..=1.0).contains(&label_smoothing) {
+            return Err(Error::InvalidArgument(
+                "Label smoothing must be within [0, 1]".to_string(),
+            ));
+        }
+        self.label_smoothing = label_smoothing;
+        Ok(self)
+    }
+
+    // One weight per class, the count is compared with the labels in `check_labels`
+    pub fn with_class_weights(mut self, class_weights: Vec<f64>) -> Result<Self> {
+        if class_weights.is_empty() || !class_weights.iter().all(|w| w.is_finite() && *w >= 0.0) {
+            return Err(Error::InvalidArgument(
+                "Class weights must be a non-empty list of finite, non-negative values".to_string(),
+            ));
+        }
+        self.class_weights = Some(class_weights);
+        Ok(self)
+    }
+
+    // Smoothed target of every class multiplied by its class weight
+    fn weighted_targets(&self, actual: &Matrix) -> Matrix {
+        let classes = actual.rows as f64;
+        let eps = self.label_smoothing;
+        let smoothed = actual.map(|y| (1.0 - eps) * y + eps / classes);
+        match &self.class_weights {
+            Some(weights) => &smoothed * &Matrix::new(weights.len(), 1, weights.clone()),
+            None => smoothed,
+        }
+    }
+}
+
+impl Loss for SoftmaxCrossEntropy {
+    fn value(&self, prediction: &Matrix, actual: &Matrix) -> f64 {
+        self.value_and_gradient(prediction, actual).0
+    }
+
+    fn gradient(&self, prediction: &Matrix, actual: &Matrix) -> Matrix {
+        self.value_and_gradient(prediction, actual).1
+    }
+
+    fn takes_logits(&self) -> bool {
+        true
+    }
+
+    fn check_labels(&self, prediction: &Matrix, actual: &Matrix) -> Result<()> {
+        dimensions_match(prediction, actual)?;
+        match &self.class_weights {
+            Some(weights) if weights.len() != actual.rows => Err(Error::Shape(format!(
+                "{} class weights for {} classes",
+                weights.len(),
+                actual.rows
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    // With t the weighted targets: loss = -sum(t * log_softmax(z)), dloss/dz = softmax(z) * sum(t) - t
+    fn value_and_gradient(&self, prediction: &Matrix, actual: &Matrix) -> (f64, Matrix) {
+        check_dimensions(prediction, actual);
+        let t = self.weighted_targets(actual);
+        let log_p = log_softmax(prediction);
+
+        let loss = -(&t * &log_p).data().iter().sum::<f64>() / actual.cols as f64;
+        let gradient = &(&softmax(prediction) * &t.sum_rows()) - &t;
+        (loss, gradient)
+    }
+
+    fn to_saved(&self) -> Option<SavedLoss> {
+        Some(SavedLoss::SoftmaxCrossEntropy(self.clone()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Mse;
+
+impl Loss for Mse {
+    fn value(&self, prediction: &Matrix, actual: &Matrix) -> f64 {
+        mse(prediction, actual)
+    }
+
+    fn gradient(&self, prediction: &Matrix, actual: &Matrix) -> Matrix {
+        d_mse(prediction, actual)
+    }
+
+    fn to_saved(&self) -> Option<SavedLoss> {
+        Some(SavedLoss::Mse(self.clone()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Mae;
+
+impl Loss for Mae {
+    fn value(&self, prediction: &Matrix, actual: &Matrix) -> f64 {
+        mae(prediction, actual)
+    }
+
+    fn gradient(&self, prediction: &Matrix, actual: &Matrix) -> Matrix {
+        d_mae(prediction, actual)
+    }
+
+    fn to_saved(&self) -> Option<SavedLoss> {
+        Some(SavedLoss::Mae(self.clone()))
+    }
+}
+
+// Smooth L1, quadratic for errors within `delta` and linear beyond
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Huber {
+    pub delta: f64,
+}
+
+impl Default for Huber {
+    fn default() -> Self {
+        Huber { delta: 1.0 }
+    }
+}
+
+impl Loss for Huber {
+    fn value(&self, prediction: &Matrix, actual: &Matrix) -> f64 {
+        huber(prediction, actual, self.delta)
+    }
+
+    fn gradient(&self, prediction: &Matrix, actual: &Matrix) -> Matrix {
+        d_huber(prediction, actual, self.delta)
+    }
+
+    fn to_saved(&self) -> Option<SavedLoss> {
+        Some(SavedLoss::Huber(self.clone()))
+    }
+}
+
+// Multi-class SVM loss on raw scores, `actual` is one-hot
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hinge {
+    pub margin: f64,
+}
+
+impl Default for Hinge {
+    fn default() -> Self {
+        Hinge { margin: 1.0 }
+    }
+}
+
+impl Loss for Hinge {
+    fn value(&self, prediction: &Matrix, actual: &Matrix) -> f64 {
+        hinge(prediction, actual, self.margin)
+    }
+
+    fn gradient(&self, prediction: &Matrix, actual: &Matrix) -> Matrix {
+        d_hinge(prediction, actual, self.margin)
+    }
+
+    fn to_saved(&self) -> Option<SavedLoss> {
+        Some(SavedLoss::Hinge(self.clone()))
+    }
+}
+
+// KL(actual || prediction), both columns are probability distributions
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KlDivergence;
+
+impl Loss for KlDivergence {
+    fn value(&self, prediction: &Matrix, actual: &Matrix) -> f64 {
+        kl_divergence(prediction, actual)
+    }
+
+    fn gradient(&self, prediction: &Matrix, actual: &Matrix) -> Matrix {
+        d_kl_divergence(prediction, actual)
+    }
+
+    fn to_saved(&self) -> Option<SavedLoss> {
+        Some(SavedLoss::KlDivergence(self.clone()))
+    }
+}
+
+// Cross-entropy down-weighting well classified samples by (1 - p)^gamma, on probabilities
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Focal {
+    pub gamma: f64,
+    pub alpha: f64,
+}
+
+impl Default for Focal {
+    fn default() -> Self {
+        Focal {
+            gamma: 2.0,
+            alpha: 1.0,
+        }
+    }
+}
+
+impl Loss for Focal {
+    fn value(&self, prediction: &Matrix, actual: &Matrix) -> f64 {
+        focal(prediction, actual, self.gamma, self.alpha)
+    }
+
+    fn gradient(&self, prediction: &Matrix, actual: &Matrix) -> Matrix {
+        d_focal(prediction, actual, self.gamma, self.alpha)
+    }
+
+    fn to_saved(&self) -> Option<SavedLoss> {
+        Some(SavedLoss::Focal(self.clone()))
+    }
+}
+
+// Negative log-likelihood of log-probabilities (e.g. an `Activation::LogSoftmax` output).
+// `actual` holds class indices instead of one-hot columns, a (1 x batch_size) matrix built by `class_indices`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Nll;
+
+impl Loss for Nll {
+    fn value(&self, prediction: &Matrix, actual: &Matrix) -> f64 {
+        nll(prediction, actual)
+    }
+
+    fn gradient(&self, prediction: &Matrix, actual: &Matrix) -> Matrix {
+        d_nll(prediction, actual)
     }
 
-    -loss
+    fn check_labels(&self, prediction: &Matrix, actual: &Matrix) -> Result<()> {
+        valid_class_indices(prediction, actual)
+    }
+
+    fn to_saved(&self) -> Option<SavedLoss> {
+        Some(SavedLoss::Nll(self.clone()))
+    }
+}
+
+pub fn sse(prediction: &Matrix, actual: &Matrix) -> f64 {
+    check_dimensions(prediction, actual);
+
+    sum_pairs(prediction, actual, |p, y| (p - y).powi(2)) / actual.cols as f64
+}
+
+pub fn d_sse(prediction: &Matrix, actual: &Matrix) -> Matrix {
+    check_dimensions(prediction, actual);
+    prediction.zip_map(actual, |p, y| 2.0 * (p - y))
+}
+
+// Suitable for multi-class classification
+pub fn cross_entropy(prediction: &Matrix, actual: &Matrix) -> f64 {
+    check_dimensions(prediction, actual);
+
+    -sum_pairs(prediction, actual, |p, y| y * p.ln()) / actual.cols as f64
+}
+
+pub fn d_cross_entropy(prediction: &Matrix, actual: &Matrix) -> Matrix {
+    check_dimensions(prediction, actual);
+    prediction.zip_map(actual, |p, y| -y / p)
 }
 
 // Suitable for binary classification
 pub fn binary_cross_entropy(prediction: &Matrix, actual: &Matrix) -> f64 {
-    if actual.rows != prediction.rows {
-        panic!("Dimensions do not match");
+    check_dimensions(prediction, actual);
+
+    let loss = sum_pairs(prediction, actual, |p, y| {
+        -(y * p.ln() + (1.0 - y) * (1.0 - p).ln())
+    });
+
+    loss / actual.cols as f64
+}
+
+pub fn d_binary_cross_entropy(prediction: &Matrix, actual: &Matrix) -> Matrix {
+    check_dimensions(prediction, actual);
+    prediction.zip_map(actual, |p, y| (p - y) / (p * (1.0 - p)))
+}
+
+// Mean over every output of every sample
+pub fn mse(prediction: &Matrix, actual: &Matrix) -> f64 {
+    check_dimensions(prediction, actual);
+    sse(prediction, actual) / actual.rows as f64
+}
+
+pub fn d_mse(prediction: &Matrix, actual: &Matrix) -> Matrix {
+    check_dimensions(prediction, actual);
+    let n = actual.rows as f64;
+    prediction.zip_map(actual, |p, y| 2.0 * (p - y) / n)
+}
+
+pub fn mae(prediction: &Matrix, actual: &Matrix) -> f64 {
+    check_dimensions(prediction, actual);
+
+    sum_pairs(prediction, actual, |p, y| (p - y).abs()) / (actual.rows * actual.cols) as f64
+}
+
+// The derivative at p == y is taken as 0
+pub fn d_mae(prediction: &Matrix, actual: &Matrix) -> Matrix {
+    check_dimensions(prediction, actual);
+    let n = actual.rows as f64;
+    prediction.zip_map(
+        actual,
+        |p, y| {
+            if p == y {
+                0.0
+            } else {
+                (p - y).signum() / n
+            }
+        },
+    )
+}
+
+pub fn huber(prediction: &Matrix, actual: &Matrix, delta: f64) -> f64 {
+    check_dimensions(prediction, actual);
+
+    let loss = sum_pairs(prediction, actual, |p, y| {
+        let d = (p - y).abs();
+        if d <= delta {
+            0.5 * d * d
+        } else {
+            delta * (d - 0.5 * delta)
+        }
+    });
+
+    loss / (actual.rows * actual.cols) as f64
+}
+
+pub fn d_huber(prediction: &Matrix, actual: &Matrix, delta: f64) -> Matrix {
+    check_dimensions(prediction, actual);
+    let n = actual.rows as f64;
+    prediction.zip_map(actual, |p, y| (p - y).clamp(-delta, delta) / n)
+}
+
+// sum over wrong classes i of max(0, s_i - s_y + margin)
+pub fn hinge(prediction: &Matrix, actual: &Matrix, margin: f64) -> f64 {
+    check_dimensions(prediction, actual);
+
+    let mut loss = 0.0;
+    for j in 0..actual.cols {
+        let y = target_class(actual, j);
+        let correct = prediction.get(y, j);
+        for i in (0..actual.rows).filter(|&i| i != y) {
+            loss += (prediction.get(i, j) - correct + margin).max(0.0);
+        }
+    }
+
+    loss / actual.cols as f64
+}
+
+pub fn d_hinge(prediction: &Matrix, actual: &Matrix, margin: f64) -> Matrix {
+    check_dimensions(prediction, actual);
+
+    let mut data = vec![0.0; actual.rows * actual.cols];
+    for j in 0..actual.cols {
+        let y = target_class(actual, j);
+        let correct = prediction.get(y, j);
+        for i in (0..actual.rows).filter(|&i| i != y) {
+            if prediction.get(i, j) - correct + margin > 0.0 {
+                data[i * actual.cols + j] += 1.0;
+                data[y * actual.cols + j] -= 1.0;
+            }
+        }
     }
 
+    Matrix::new(actual.rows, actual.cols, data)
+}
+
+// sum(y * ln(y / p)), with 0 * ln(0) taken as 0
+pub fn kl_divergence(prediction: &Matrix, actual: &Matrix) -> f64 {
+    check_dimensions(prediction, actual);
+
+    let loss = sum_pairs(prediction, actual, |p, y| match y > 0.0 {
+        true => y * (y / p).ln(),
+        false => 0.0,
+    });
+
+    loss / actual.cols as f64
+}
+
+pub fn d_kl_divergence(prediction: &Matrix, actual: &Matrix) -> Matrix {
+    d_cross_entropy(prediction, actual)
+}
+
+// -alpha * (1 - p)^gamma * y * ln(p)
+pub fn focal(prediction: &Matrix, actual: &Matrix, gamma: f64, alpha: f64) -> f64 {
+    check_dimensions(prediction, actual);
+
+    let loss = sum_pairs(prediction, actual, |p, y| match y > 0.0 {
+        true => -alpha * (1.0 - p).powf(gamma) * y * p.ln(),
+        false => 0.0,
+    });
+
+    loss / actual.cols as f64
+}
+
+pub fn d_focal(prediction: &Matrix, actual: &Matrix, gamma: f64, alpha: f64) -> Matrix {
+    check_dimensions(prediction, actual);
+    prediction.zip_map(actual, |p, y| {
+        if y == 0.0 {
+            return 0.0;
+        }
+        let q = 1.0 - p;
+        alpha * y * (gamma * q.powf(gamma - 1.0) * p.ln() - q.powf(gamma) / p)
+    })
+}
+
+// Builds the (1 x batch_size) label matrix `Nll` expects
+pub fn class_indices(labels: &[usize]) -> Matrix {
+    Matrix::new(1, labels.len(), labels.iter().map(|&l| l as f64).collect())
+}
+
+pub fn nll(prediction: &Matrix, actual: &Matrix) -> f64 {
+    check_class_indices(prediction, actual);
+
     let mut loss = 0.0;
-    for i in 0..actual.rows {
-        let p = prediction.data()[i];
-        let y = actual.data()[i];
-        loss -= y * p.ln() + (1.0 - y) * (1.0 - p).ln();
+    for j in 0..actual.cols {
+        loss -= prediction.get(actual.get(0, j) as usize, j);
+    }
+
+    loss / actual.cols as f64
+}
+
+pub fn d_nll(prediction: &Matrix, actual: &Matrix) -> Matrix {
+    check_class_indices(prediction, actual);
+
+    let mut data = vec![0.0; prediction.rows * prediction.cols];
+    for j in 0..actual.cols {
+        data[actual.get(0, j) as usize * prediction.cols + j] = -1.0;
+    }
+
+    Matrix::new(prediction.rows, prediction.cols, data)
+}
+
+// Sum of `op` over every (prediction, label) pair. Entries are read through `get`, so transposed
+// matrices line up with their labels.
+fn sum_pairs(prediction: &Matrix, actual: &Matrix, op: impl Fn(f64, f64) -> f64) -> f64 {
+    (0..actual.rows)
+        .flat_map(|i| (0..actual.cols).map(move |j| (i, j)))
+        .map(|(i, j)| op(prediction.get(i, j), actual.get(i, j)))
+        .sum()
+}
+
+// Index of the largest entry of column `j` of one-hot labels
+#[inline]
+fn target_class(actual: &Matrix, j: usize) -> usize {
+    (0..actual.rows)
+        .max_by(|&a, &b| actual.get(a, j).total_cmp(&actual.get(b, j)))
+        .unwrap_or(0)
+}
+
+fn valid_class_indices(prediction: &Matrix, actual: &Matrix) -> Result<()> {
+    if actual.rows != 1 || actual.cols != prediction.cols {
+        return Err(Error::Shape(
+            "Expected a (1 x batch_size) matrix of class indices".to_string(),
+        ));
+    }
+    if actual
+        .data()
+        .iter()
+        .any(|&c| c < 0.0 || c.fract() != 0.0 || c as usize >= prediction.rows)
+    {
+        return Err(Error::Shape("Class index out of range".to_string()));
+    }
+    Ok(())
+}
+
+fn dimensions_match(prediction: &Matrix, actual: &Matrix) -> Result<()> {
+    if actual.rows != prediction.rows || actual.cols != prediction.cols {
+        return Err(Error::Shape(format!(
+            "Labels are ({} x {}) but predictions ({} x {})",
+            actual.rows, actual.cols, prediction.rows, prediction.cols
+        )));
+    }
+    Ok(())
+}
+
+#[inline]
+fn check_class_indices(prediction: &Matrix, actual: &Matrix) {
+    if let Err(e) = valid_class_indices(prediction, actual) {
+        panic!("{}", e);
+    }
+}
+
+#[inline]
+fn check_dimensions(prediction: &Matrix, actual: &Matrix) {
+    if let Err(e) = dimensions_match(prediction, actual) {
+        panic!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_approx_eq(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "left = {}, right = {}", a, b);
+    }
+
+    // Compares the analytic gradient against central differences of the summed (not averaged) loss
+    fn check_gradient(loss: &dyn Loss, prediction: &Matrix, actual: &Matrix) {
+        let h = 1e-6;
+        let batch = prediction.cols as f64;
+        let grad = loss.gradient(prediction, actual);
+        for i in 0..prediction.data().len() {
+            let mut plus = prediction.data().to_vec();
+            let mut minus = prediction.data().to_vec();
+            plus[i] += h;
+            minus[i] -= h;
+            let plus = Matrix::new(prediction.rows, prediction.cols, plus);
+            let minus = Matrix::new(prediction.rows, prediction.cols, minus);
+            let numeric =
+                batch * (loss.value(&plus, actual) - loss.value(&minus, actual)) / (2.0 * h);
+            assert_approx_eq(grad.data()[i], numeric);
+        }
+    }
+
+    #[test]
+    fn test_sse_batch_mean() {
+        let p = Matrix::new(2, 2, vec![1.0, 0.0, 0.0, 1.0]);
+        let y = Matrix::new(2, 2, vec![0.0, 0.0, 0.0, 0.0]);
+        // Each sample has a squared error of 1
+        assert_approx_eq(Sse.value(&p, &y), 1.0);
+    }
+
+    #[test]
+    fn test_cross_entropy_value() {
+        let p = Matrix::new(2, 1, vec![0.25, 0.75]);
+        let y = Matrix::new(2, 1, vec![0.0, 1.0]);
+        assert_approx_eq(CrossEntropy.value(&p, &y), -(0.75_f64.ln()));
+    }
+
+    #[test]
+    fn test_gradients_match_numeric() {
+        let p = Matrix::new(3, 2, vec![0.2, 0.6, 0.5, 0.3, 0.3, 0.1]);
+        let y = Matrix::new(3, 2, vec![0.0, 1.0, 1.0, 0.0, 0.0, 0.0]);
+        check_gradient(&Sse, &p, &y);
+        check_gradient(&CrossEntropy, &p, &y);
+        check_gradient(&BinaryCrossEntropy, &p, &y);
+    }
+
+    #[test]
+    fn test_cross_entropy_softmax_gradient() {
+        let p = Matrix::new(2, 1, vec![0.25, 0.75]);
+        let y = Matrix::new(2, 1, vec![0.0, 1.0]);
+        let g = CrossEntropy.softmax_gradient(&p, &y).unwrap();
+        assert_approx_eq(g.data()[0], 0.25);
+        assert_approx_eq(g.data()[1], -0.25);
+        assert!(Sse.softmax_gradient(&p, &y).is_none());
     }
 
-    loss
+    #[test]
+    #[should_panic]
+    fn test_dimension_mismatch() {
+        let p = Matrix::new(2, 2, vec![0.5; 4]);
+        let y = Matrix::new(2, 1, vec![0.5; 2]);
+        let _ = Sse.value(&p, &y);
+    }
+
+    #[test]
+    fn test_softmax_cross_entropy_matches_cross_entropy() {
+        let z = Matrix::new(3, 2, vec![1.0, -1.0, 2.0, 0.0, 0.5, 3.0]);
+        let y = Matrix::new(3, 2, vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0]);
+        let (loss, gradient) = SoftmaxCrossEntropy::new().value_and_gradient(&z, &y);
+
+        let p = softmax(&z);
+        assert_approx_eq(loss, cross_entropy(&p, &y));
+        let expected = &p - &y;
+        for i in 0..6 {
+            assert_approx_eq(gradient.data()[i], expected.data()[i]);
+        }
+    }
+
+    #[test]
+    fn test_softmax_cross_entropy_large_logits() {
+        let z = Matrix::new(2, 1, vec![-1000.0, 1000.0]);
+        let y = Matrix::new(2, 1, vec![1.0, 0.0]);
+        let (loss, gradient) = SoftmaxCrossEntropy::new().value_and_gradient(&z, &y);
+        assert_approx_eq(loss, 2000.0);
+        assert!(gradient.data().iter().all(|g| g.is_finite()));
+    }
+
+    #[test]
+    fn test_softmax_cross_entropy_label_smoothing() {
+        // Uniform logits with full smoothing, the target is uniform too so the gradient vanishes
+        let z = Matrix::new(4, 1, vec![0.0; 4]);
+        let y = Matrix::new(4, 1, vec![1.0, 0.0, 0.0, 0.0]);
+        let loss = SoftmaxCrossEntropy::new()
+            .with_label_smoothing(1.0)
+            .unwrap();
+        let g = loss.gradient(&z, &y);
+        assert!(g.data().iter().all(|g| g.abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_softmax_cross_entropy_gradient_matches_numeric() {
+        let z = Matrix::new(3, 2, vec![0.2, -1.6, 1.5, 0.3, -0.3, 0.9]);
+        let y = Matrix::new(3, 2, vec![0.0, 1.0, 1.0, 0.0, 0.0, 0.0]);
+        let loss = SoftmaxCrossEntropy::new()
+            .with_label_smoothing(0.1)
+            .and_then(|l| l.with_class_weights(vec![0.5, 2.0, 1.0]))
+            .unwrap();
+        check_gradient(&loss, &z, &y);
+    }
+
+    #[test]
+    fn test_losses_follow_strides() {
+        // The same predictions stored row by row and column by column
+        let p = Matrix::new(2, 3, vec![0.1, 0.7, 0.4, 0.9, 0.3, 0.6]);
+        let mut t = Matrix::new(3, 2, vec![0.1, 0.9, 0.7, 0.3, 0.4, 0.6]);
+        t.transpose();
+        let y = Matrix::new(2, 3, vec![0.0, 1.0, 1.0, 1.0, 0.0, 0.0]);
+
+        let losses: [&dyn Loss; 7] = [
+            &Sse,
+            &CrossEntropy,
+            &BinaryCrossEntropy,
+            &Mae,
+            &Huber { delta: 0.25 },
+            &KlDivergence,
+            &Focal::default(),
+        ];
+        for loss in losses {
+            assert_approx_eq(loss.value(&t, &y), loss.value(&p, &y));
+        }
+    }
+
+    #[test]
+    fn test_regression_losses() {
+        let p = Matrix::new(2, 2, vec![1.0, 3.0, 0.0, -2.0]);
+        let y = Matrix::new(2, 2, vec![0.0, 0.0, 0.0, 0.0]);
+        assert_approx_eq(Mse.value(&p, &y), (1.0 + 9.0 + 4.0) / 4.0);
+        assert_approx_eq(Mae.value(&p, &y), (1.0 + 3.0 + 2.0) / 4.0);
+        // 0.5 * 1^2 for the first error, the others are linear: 1 * (|d| - 0.5)
+        assert_approx_eq(Huber::default().value(&p, &y), (0.5 + 2.5 + 1.5) / 4.0);
+    }
+
+    #[test]
+    fn test_hinge_value() {
+        let s = Matrix::new(3, 1, vec![3.2, 5.1, -1.7]);
+        let y = Matrix::new(3, 1, vec![1.0, 0.0, 0.0]);
+        // max(0, 5.1 - 3.2 + 1) + max(0, -1.7 - 3.2 + 1)
+        assert_approx_eq(Hinge::default().value(&s, &y), 2.9);
+    }
+
+    #[test]
+    fn test_kl_divergence_of_identical_distributions() {
+        let p = Matrix::new(3, 1, vec![0.2, 0.3, 0.5]);
+        assert_approx_eq(KlDivergence.value(&p, &p), 0.0);
+    }
+
+    #[test]
+    fn test_focal_without_focusing_is_cross_entropy() {
+        let p = Matrix::new(2, 1, vec![0.25, 0.75]);
+        let y = Matrix::new(2, 1, vec![0.0, 1.0]);
+        let focal = Focal {
+            gamma: 0.0,
+            alpha: 1.0,
+        };
+        assert_approx_eq(focal.value(&p, &y), CrossEntropy.value(&p, &y));
+        assert!(Focal::default().value(&p, &y) < CrossEntropy.value(&p, &y));
+    }
+
+    #[test]
+    fn test_nll_with_class_indices() {
+        let log_p = log_softmax(&Matrix::new(3, 2, vec![1.0, 0.0, 2.0, 0.0, 0.5, 3.0]));
+        let labels = class_indices(&[1, 2]);
+        let one_hot = Matrix::new(3, 2, vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0]);
+        let expected = SoftmaxCrossEntropy::new().value(
+            &Matrix::new(3, 2, vec![1.0, 0.0, 2.0, 0.0, 0.5, 3.0]),
+            &one_hot,
+        );
+        assert_approx_eq(Nll.value(&log_p, &labels), expected);
+    }
+
+    #[test]
+    fn test_check_labels() {
+        let p = Matrix::new(3, 2, vec![0.2; 6]);
+        let one_hot = Matrix::new(3, 2, vec![1.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+        let indices = class_indices(&[0, 2]);
+
+        assert!(CrossEntropy.check_labels(&p, &one_hot).is_ok());
+        assert!(CrossEntropy.check_labels(&p, &indices).is_err());
+        assert!(Nll.check_labels(&p, &indices).is_ok());
+        assert!(Nll.check_labels(&p, &class_indices(&[0, 3])).is_err());
+        let weighted = SoftmaxCrossEntropy::new()
+            .with_class_weights(vec![1.0, 2.0])
+            .unwrap();
+        assert!(matches!(
+            weighted.check_labels(&p, &one_hot),
+            Err(Error::Shape(_))
+        ));
+    }
+
+    #[test]
+    fn test_softmax_cross_entropy_rejects_bad_parameters() {
+        let loss = SoftmaxCrossEntropy::new;
+        assert!(matches!(
+            loss().with_label_smoothing(1.5),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            loss().with_label_smoothing(-0.1),
+            Err(Error::InvalidArgument(_))
+        ));
+        for weights in [vec![], vec![1.0, -1.0], vec![f64::NAN]] {
+            assert!(matches!(
+                loss().with_class_weights(weights),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_nll_class_out_of_range() {
+        let log_p = Matrix::new(3, 1, vec![-1.0; 3]);
+        let _ = Nll.value(&log_p, &class_indices(&[3]));
+    }
+
+    #[test]
+    fn test_new_loss_gradients_match_numeric() {
+        let p = Matrix::new(3, 2, vec![0.2, 0.6, 0.5, 0.3, 0.3, 0.1]);
+        let y = Matrix::new(3, 2, vec![0.0, 1.0, 1.0, 0.0, 0.0, 0.0]);
+        check_gradient(&Mse, &p, &y);
+        check_gradient(&Mae, &p, &y);
+        check_gradient(&Huber { delta: 0.25 }, &p, &y);
+        check_gradient(&Hinge { margin: 0.25 }, &p, &y);
+        check_gradient(&KlDivergence, &p, &y);
+        check_gradient(&Focal::default(), &p, &y);
+        check_gradient(&Nll, &p, &class_indices(&[1, 0]));
+    }
 }
@@ -1,9 +1,21 @@
+use crate::error::{Error, Result};
 use rand::distr::{Distribution, Uniform};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
 use std::fmt::{Display, Formatter};
 use std::ops::{Add, Mul, Sub};
 
-#[derive(Serialize, Deserialize, Debug)]
+// Which vectors an axis-aware operation works on: every column (one sample per column) or every row.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Axis {
+    Row,
+    Column,
+}
+
+// Deserialised through `StoredMatrix` so a malformed file can't produce out of range strides
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "StoredMatrix")]
 pub struct Matrix {
     pub rows: usize,
     pub cols: usize,
@@ -12,18 +24,63 @@ pub struct Matrix {
     data: Vec<f64>,
 }
 
+#[derive(Deserialize)]
+struct StoredMatrix {
+    rows: usize,
+    cols: usize,
+    row_stride: usize,
+    col_stride: usize,
+    data: Vec<f64>,
+}
+
+// Only the layouts `new` and `transpose` create are accepted: row-major, or its transpose
+impl TryFrom<StoredMatrix> for Matrix {
+    type Error = Error;
+
+    fn try_from(m: StoredMatrix) -> Result<Self> {
+        let mut matrix = Matrix::try_new(m.rows, m.cols, m.data)?;
+        match (m.row_stride, m.col_stride) {
+            (r, 1) if r == m.cols => {}
+            (1, c) if c == m.rows => {
+                matrix = Matrix::try_new(m.cols, m.rows, matrix.data)?;
+                matrix.transpose();
+            }
+            strides => {
+                return Err(Error::Format(format!(
+                    "Strides {:?} are invalid for a ({} x {}) matrix",
+                    strides, m.rows, m.cols
+                )))
+            }
+        }
+        Ok(matrix)
+    }
+}
+
 impl Matrix {
+    // Panics when `data` doesn't hold `rows * cols` values, use `try_new` for data from outside the crate.
     pub fn new(rows: usize, cols: usize, data: Vec<f64>) -> Self {
-        if rows * cols != data.len() {
-            panic!("Data length does not match dimensions");
+        match Matrix::try_new(rows, cols, data) {
+            Ok(m) => m,
+            Err(e) => panic!("{}", e),
         }
-        Self {
+    }
+
+    pub fn try_new(rows: usize, cols: usize, data: Vec<f64>) -> Result<Self> {
+        if rows.checked_mul(cols) != Some(data.len()) {
+            return Err(Error::Shape(format!(
+                "{} values can't fill a ({} x {}) matrix",
+                data.len(),
+                rows,
+                cols
+            )));
+        }
+        Ok(Self {
             data,
             rows,
             cols,
             col_stride: 1,
             row_stride: cols,
-        }
+        })
     }
 
     pub fn data(&self) -> &[f64] {
@@ -31,19 +88,40 @@ impl Matrix {
     }
 
     #[inline]
-    fn get(&self, row: usize, col: usize) -> f64 {
+    pub fn get(&self, row: usize, col: usize) -> f64 {
         self.data[row * self.row_stride + col * self.col_stride]
     }
 
-    pub fn uniform(rows: usize, cols: usize) -> Self {
-        let mut rng = rand::rng();
+    // U(-1, 1) entries drawn from `rng`
+    pub fn uniform(rows: usize, cols: usize, rng: &mut impl Rng) -> Self {
         let distribution = Uniform::try_from(-1.0..1.0).unwrap();
-        let data = (0..rows * cols)
-            .map(|_| distribution.sample(&mut rng))
-            .collect();
+        let data = (0..rows * cols).map(|_| distribution.sample(rng)).collect();
         Matrix::new(rows, cols, data)
     }
 
+    // Stacks column vectors side by side into a (rows x columns.len()) matrix, one sample per column.
+    pub fn from_columns<M: Borrow<Matrix>>(columns: &[M]) -> Result<Self> {
+        let rows = columns.first().map_or(0, |c| c.borrow().rows);
+        if columns
+            .iter()
+            .any(|c| c.borrow().rows != rows || c.borrow().cols != 1)
+        {
+            return Err(Error::Shape(
+                "Columns must all be (rows x 1) vectors of the same length".to_string(),
+            ));
+        }
+
+        let cols = columns.len();
+        let mut data = vec![0.0; rows * cols];
+        for (j, column) in columns.iter().enumerate() {
+            let column = column.borrow();
+            for i in 0..rows {
+                data[i * cols + j] = column.get(i, 0);
+            }
+        }
+        Ok(Matrix::new(rows, cols, data))
+    }
+
     pub fn eye(size: usize) -> Self {
         let mut data = vec![0.0; size * size];
         for i in 0..size {
@@ -62,9 +140,12 @@ impl Matrix {
         std::mem::swap(&mut self.row_stride, &mut self.col_stride);
     }
 
-    pub fn dot(&self, other: &Matrix) -> Result<Matrix, &'static str> {
+    pub fn dot(&self, other: &Matrix) -> Result<Matrix> {
         if self.cols != other.rows {
-            return Err("Matrix multiplication dimension mismatch: A.cols != B.rows");
+            return Err(Error::Shape(format!(
+                "Can't multiply a ({} x {}) matrix by a ({} x {}) matrix",
+                self.rows, self.cols, other.rows, other.cols
+            )));
         }
 
         let mut data = vec![0.0; self.rows * other.cols];
@@ -96,36 +177,121 @@ impl Matrix {
         let mut data = vec![0.0; self.cols * self.rows];
         for i in 0..self.rows {
             for j in 0..self.cols {
-                data[i] = self.get(i, j).powi(exp);
+                data[i * self.cols + j] = self.get(i, j).powi(exp);
+            }
+        }
+        Matrix::new(self.rows, self.cols, data)
+    }
+
+    // Reduces every row to the sum of its columns, giving a (rows x 1) vector.
+    // With one sample per column this sums a batch into a single column.
+    pub fn sum_cols(&self) -> Self {
+        let data = (0..self.rows)
+            .map(|i| (0..self.cols).map(|j| self.get(i, j)).sum())
+            .collect();
+        Matrix::new(self.rows, 1, data)
+    }
+
+    // Reduces every column to the sum of its rows, giving a (1 x cols) vector of per-sample totals.
+    pub fn sum_rows(&self) -> Self {
+        let data = (0..self.cols)
+            .map(|j| (0..self.rows).map(|i| self.get(i, j)).sum())
+            .collect();
+        Matrix::new(1, self.cols, data)
+    }
+
+    // Row of the largest value in column `col`, e.g. the most likely class of a (classes x n) output.
+    pub fn argmax_col(&self, col: usize) -> usize {
+        (0..self.rows)
+            .max_by(|&a, &b| self.get(a, col).total_cmp(&self.get(b, col)))
+            .unwrap_or(0)
+    }
+
+    // Applies `op` to every row or column vector of the matrix, `op` writes its result into the output slice.
+    pub fn map_axis<F>(&self, axis: Axis, op: F) -> Matrix
+    where
+        F: Fn(&[f64], &mut [f64]),
+    {
+        let (outer, inner) = match axis {
+            Axis::Column => (self.cols, self.rows),
+            Axis::Row => (self.rows, self.cols),
+        };
+        let position = |o: usize, k: usize| match axis {
+            Axis::Column => (k, o),
+            Axis::Row => (o, k),
+        };
+
+        let mut data = vec![0.0; self.rows * self.cols];
+        let mut vector = vec![0.0; inner];
+        let mut result = vec![0.0; inner];
+        for o in 0..outer {
+            for (k, v) in vector.iter_mut().enumerate() {
+                let (i, j) = position(o, k);
+                *v = self.get(i, j);
+            }
+            op(&vector, &mut result);
+            for (k, r) in result.iter().enumerate() {
+                let (i, j) = position(o, k);
+                data[i * self.cols + j] = *r;
+            }
+        }
+        Matrix::new(self.rows, self.cols, data)
+    }
+
+    pub fn map<F>(&self, op: F) -> Matrix
+    where
+        F: Fn(f64) -> f64,
+    {
+        let mut data = Vec::with_capacity(self.rows * self.cols);
+        for i in 0..self.rows {
+            for j in 0..self.cols {
+                data.push(op(self.get(i, j)));
             }
         }
         Matrix::new(self.rows, self.cols, data)
     }
 
-    fn zip_map<F>(&self, other: &Matrix, op: F) -> Matrix
+    // Panics when the shapes can't be broadcast, as do the `+`, `-` and `*` operators built on it.
+    pub fn zip_map<F>(&self, other: &Matrix, op: F) -> Matrix
+    where
+        F: Fn(f64, f64) -> f64,
+    {
+        match self.try_zip_map(other, op) {
+            Ok(m) => m,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    pub fn try_zip_map<F>(&self, other: &Matrix, op: F) -> Result<Matrix>
     where
         F: Fn(f64, f64) -> f64,
     {
         // Scalar case
         if other.data.len() == 1 {
             let scalar = other.data[0];
-            let data = self.data.iter().map(|&x| op(x, scalar)).collect();
-            return Matrix::new(self.rows, self.cols, data);
-        }
-
-        // Dimension Check
-        if self.cols != other.cols {
-            panic!("Dimensions are not compatible: cols mismatch");
+            return Ok(self.map(|x| op(x, scalar)));
         }
 
         let output_rows = self.rows;
         let output_cols = self.cols;
         let mut new_data = Vec::with_capacity(output_rows * output_cols);
 
+        // Column Broadcasting logic, e.g. a (n x 1) bias added to every sample of a (n x batch) matrix
+        let broadcast_cols = other.cols == 1;
+        if self.cols != other.cols && !broadcast_cols {
+            return Err(Error::Shape(format!(
+                "Can't broadcast {} columns to {}",
+                other.cols, self.cols
+            )));
+        }
+
         // Row Broadcasting logic
         let broadcast_rows = other.rows == 1;
         if self.rows != other.rows && !broadcast_rows {
-            panic!("Dimensions are not compatible: rows mismatch");
+            return Err(Error::Shape(format!(
+                "Can't broadcast {} rows to {}",
+                other.rows, self.rows
+            )));
         }
 
         // Iterate logically (i, j) and access physical data via strides
@@ -133,15 +299,16 @@ impl Matrix {
             for j in 0..output_cols {
                 let val_self = self.get(i, j);
 
-                // If broadcasting B (1 row), always use row 0
+                // If broadcasting B (1 row / 1 col), always use row / col 0
                 let row_other = if broadcast_rows { 0 } else { i };
-                let val_other = other.get(row_other, j);
+                let col_other = if broadcast_cols { 0 } else { j };
+                let val_other = other.get(row_other, col_other);
 
                 new_data.push(op(val_self, val_other));
             }
         }
 
-        Matrix::new(output_rows, output_cols, new_data)
+        Ok(Matrix::new(output_rows, output_cols, new_data))
     }
 }
 
@@ -547,4 +714,163 @@ mod tests {
 
         assert_eq!(res.data, [3.0, 5.0, 7.0]);
     }
+
+    #[test]
+    fn test_broadcast_col_broadcast_add() {
+        // (2x3) batch + (2x1) bias, bias is added to every column
+        let m = Matrix::new(2, 3, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let b = Matrix::new(2, 1, vec![10.0, 20.0]);
+
+        let res = &m + &b;
+
+        assert_eq!(res.data, [11.0, 12.0, 13.0, 24.0, 25.0, 26.0]);
+    }
+
+    #[test]
+    fn test_from_columns() {
+        let c1 = Matrix::new(3, 1, vec![1.0, 2.0, 3.0]);
+        let c2 = Matrix::new(3, 1, vec![4.0, 5.0, 6.0]);
+
+        let res = Matrix::from_columns(&[c1, c2]).unwrap();
+
+        assert_eq!(res.rows, 3);
+        assert_eq!(res.cols, 2);
+        assert_eq!(res.data, [1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+    }
+
+    #[test]
+    fn test_from_columns_length_mismatch() {
+        let c1 = Matrix::new(3, 1, vec![1.0, 2.0, 3.0]);
+        let c2 = Matrix::new(2, 1, vec![4.0, 5.0]);
+
+        assert!(matches!(
+            Matrix::from_columns(&[c1, c2]),
+            Err(Error::Shape(_))
+        ));
+    }
+
+    #[test]
+    fn test_try_new_and_try_zip_map() {
+        assert!(matches!(
+            Matrix::try_new(2, 3, vec![1.0; 5]),
+            Err(Error::Shape(_))
+        ));
+        let m = Matrix::try_new(2, 3, vec![1.0; 6]).unwrap();
+        let b = Matrix::new(1, 2, vec![1.0; 2]);
+        assert!(matches!(
+            m.try_zip_map(&b, |x, y| x + y),
+            Err(Error::Shape(_))
+        ));
+        assert!(matches!(m.dot(&m), Err(Error::Shape(_))));
+    }
+
+    #[test]
+    fn test_sum_cols() {
+        let m = Matrix::new(2, 3, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+
+        let res = m.sum_cols();
+
+        assert_eq!(res.rows, 2);
+        assert_eq!(res.cols, 1);
+        assert_eq!(res.data, [6.0, 15.0]);
+    }
+
+    #[test]
+    fn test_sum_cols_transposed() {
+        let mut m = Matrix::new(2, 3, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        m.transpose();
+
+        let res = m.sum_cols();
+
+        assert_eq!(res.rows, 3);
+        assert_eq!(res.data, [5.0, 7.0, 9.0]);
+    }
+
+    #[test]
+    fn test_argmax_col() {
+        let mut m = Matrix::new(2, 3, vec![1.0, 7.0, 3.0, 4.0, 5.0, 6.0]);
+
+        assert_eq!(
+            (m.argmax_col(0), m.argmax_col(1), m.argmax_col(2)),
+            (1, 0, 1)
+        );
+        m.transpose();
+        assert_eq!((m.argmax_col(0), m.argmax_col(1)), (1, 2));
+    }
+
+    #[test]
+    fn test_scalar_transposed() {
+        let mut m = Matrix::new(2, 3, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        m.transpose();
+        let b = Matrix::new(1, 1, vec![2.0]);
+
+        let res = &m * &b;
+
+        assert_eq!(res.rows, 3);
+        assert_eq!(res.cols, 2);
+        assert_eq!(res.data, [2.0, 8.0, 4.0, 10.0, 6.0, 12.0]);
+    }
+
+    #[test]
+    fn test_map() {
+        let m = Matrix::new(2, 2, vec![1.0, -2.0, 3.0, -4.0]);
+
+        let res = m.map(f64::abs);
+
+        assert_eq!(res.data, [1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn test_map_axis() {
+        let m = Matrix::new(2, 2, vec![1.0, 2.0, 3.0, 4.0]);
+        let scale_by_sum = |v: &[f64], out: &mut [f64]| {
+            let sum: f64 = v.iter().sum();
+            for (o, x) in out.iter_mut().zip(v) {
+                *o = x / sum;
+            }
+        };
+
+        let cols = m.map_axis(Axis::Column, scale_by_sum);
+        assert_vec_approx_eq(&cols.data, &[0.25, 2.0 / 6.0, 0.75, 4.0 / 6.0]);
+
+        let rows = m.map_axis(Axis::Row, scale_by_sum);
+        assert_vec_approx_eq(&rows.data, &[1.0 / 3.0, 2.0 / 3.0, 3.0 / 7.0, 4.0 / 7.0]);
+    }
+
+    #[test]
+    fn test_sum_rows() {
+        let m = Matrix::new(2, 3, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+
+        let res = m.sum_rows();
+
+        assert_eq!(res.rows, 1);
+        assert_eq!(res.cols, 3);
+        assert_eq!(res.data, [5.0, 7.0, 9.0]);
+    }
+
+    #[test]
+    fn test_powi() {
+        let m = Matrix::new(2, 2, vec![1.0, 2.0, 3.0, 4.0]);
+
+        let res = m.powi(2);
+
+        assert_eq!(res.data, [1.0, 4.0, 9.0, 16.0]);
+    }
+
+    #[test]
+    fn test_deserialise_checks_strides() {
+        let mut t = Matrix::new(2, 3, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        t.transpose();
+        let restored: Matrix = serde_json::from_str(&serde_json::to_string(&t).unwrap()).unwrap();
+        assert_eq!((restored.rows, restored.cols), (3, 2));
+        assert_eq!(restored.get(2, 1), 6.0);
+
+        for json in [
+            r#"{"rows":2,"cols":2,"row_stride":100,"col_stride":1,"data":[1,2,3,4]}"#,
+            r#"{"rows":2,"cols":3,"row_stride":1,"col_stride":3,"data":[1,2,3,4,5,6]}"#,
+            r#"{"rows":2,"cols":2,"row_stride":2,"col_stride":1,"data":[1,2,3]}"#,
+        ] {
+            assert!(serde_json::from_str::<Matrix>(json).is_err());
+        }
+    }
 }
@@ -0,0 +1,93 @@
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use std::f64::consts::PI;
+
+// The one source of randomness of a run. Everything random (weight initialisation, shuffling,
+// synthetic data) draws from a `SeededRng` created from the run's seed, so the same seed gives
+// bit-identical results.
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    seed: u64,
+    rng: StdRng,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        SeededRng {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    // Seeded from the OS, for when reproducibility doesn't matter. `seed()` tells how to reproduce it.
+    pub fn from_entropy() -> Self {
+        SeededRng::new(rand::random())
+    }
+
+    // Independent generator for stream `id` of `seed`, e.g. the shuffle of one epoch. It only depends on
+    // the two values, not on how much has been drawn elsewhere, so a resumed run gets the same stream.
+    pub fn stream(seed: u64, id: u64) -> Self {
+        SeededRng::new(splitmix64(seed ^ splitmix64(id)))
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+impl RngCore for SeededRng {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dst: &mut [u8]) {
+        self.rng.fill_bytes(dst)
+    }
+}
+
+// Box-Muller transform, one standard normal sample per pair of uniforms
+pub fn standard_normal(rng: &mut impl Rng) -> f64 {
+    let u1: f64 = 1.0 - rng.random::<f64>();
+    let u2: f64 = rng.random();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+// Scrambles neighbouring seeds into unrelated ones
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    fn draws(rng: &mut SeededRng) -> Vec<f64> {
+        (0..8).map(|_| rng.random()).collect()
+    }
+
+    #[test]
+    fn test_same_seed_same_draws() {
+        assert_eq!(draws(&mut SeededRng::new(7)), draws(&mut SeededRng::new(7)));
+        assert_ne!(draws(&mut SeededRng::new(7)), draws(&mut SeededRng::new(8)));
+    }
+
+    #[test]
+    fn test_streams_are_independent() {
+        let a = draws(&mut SeededRng::stream(7, 0));
+        assert_eq!(a, draws(&mut SeededRng::stream(7, 0)));
+        assert_ne!(a, draws(&mut SeededRng::stream(7, 1)));
+        // Not just the seed shifted by the stream id
+        assert_ne!(
+            draws(&mut SeededRng::stream(7, 1)),
+            draws(&mut SeededRng::stream(8, 0))
+        );
+    }
+}
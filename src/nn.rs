@@ -1,2 +1,8 @@
 #![allow(dead_code)]
+pub mod checkpoint;
+pub mod evaluation;
+pub mod initializer;
+pub mod optimizer;
 pub mod perceptron;
+pub mod persistence;
+pub mod trainer;
@@ -0,0 +1,202 @@
+use crate::error::{Error, Result};
+use crate::math::matrix::Matrix;
+use crate::nn::optimizer::SavedOptimizer;
+use crate::nn::perceptron::Network;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+// A checkpoint is a directory holding the model in the binary format next to the training state,
+// which is everything else needed to carry on exactly where the run stopped.
+const MODEL_FILE: &str = "model.bin";
+const STATE_FILE: &str = "training_state.json";
+// Version 2 added the sample count of the gradient buffers, 3 the running metrics of the epoch
+pub const CHECKPOINT_VERSION: u32 = 3;
+
+// Where a training run has got to. No other RNG or schedule state is saved because none is needed:
+// - the shuffle of an epoch is the `SeededRng::stream` of (seed, epoch), and the augmentation of a
+//   batch the stream of (seed, epoch, batch), so they are rebuilt from these fields alone
+// - the network's own RNG only draws initial weights, which are saved in the model
+// - the step count behind a learning rate that changes over time, such as Adam's bias correction,
+//   is part of the saved optimizer state
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Progress {
+    // Epoch to run next, or the one in progress when `batch` > 0
+    pub epoch: usize,
+    // Next batch within `epoch`
+    pub batch: usize,
+    // Seed the run's randomness is derived from
+    pub seed: u64,
+    // Summed loss, correct predictions and samples of the batches of `epoch` done so far, so the
+    // metrics of an epoch resumed part way through still cover all of it
+    pub loss: f64,
+    pub correct: usize,
+    pub seen: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TrainingState {
+    pub version: u32,
+    pub progress: Progress,
+    pub optimizer: SavedOptimizer,
+    pub gradients: Vec<Matrix>,
+    pub errors: Vec<Matrix>,
+    // Samples accumulated in the gradient buffers
+    pub samples: usize,
+}
+
+pub fn exists(dir: impl AsRef<Path>) -> bool {
+    let dir = dir.as_ref();
+    dir.join(MODEL_FILE).is_file() && dir.join(STATE_FILE).is_file()
+}
+
+// Files are written under temporary names and renamed into place, so an interrupted save leaves
+// the previous checkpoint intact.
+pub fn save(dir: impl AsRef<Path>, network: &Network, progress: Progress) -> Result<()> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+
+    let state = network.training_state(progress)?;
+    let model_tmp = dir.join(format!("{}.tmp", MODEL_FILE));
+    let state_tmp = dir.join(format!("{}.tmp", STATE_FILE));
+    network.save(&model_tmp)?;
+    fs::write(&state_tmp, serde_json::to_vec(&state)?)?;
+
+    fs::rename(model_tmp, dir.join(MODEL_FILE))?;
+    fs::rename(state_tmp, dir.join(STATE_FILE))?;
+    Ok(())
+}
+
+// Rebuilds the network with its optimizer state and gradient buffers, and returns where the run stopped.
+pub fn resume_from(dir: impl AsRef<Path>) -> Result<(Network, Progress)> {
+    let dir = dir.as_ref();
+    let mut network = Network::load(dir.join(MODEL_FILE))?;
+    let state = read_state(dir)?;
+
+    let progress = state.progress;
+    network.restore_training_state(state)?;
+    Ok((network, progress))
+}
+
+// The version is read on its own first, so a checkpoint of another version is reported as such
+// instead of as a missing field.
+fn read_state(dir: &Path) -> Result<TrainingState> {
+    #[derive(Deserialize)]
+    struct Version {
+        version: u32,
+    }
+
+    let bytes = fs::read(dir.join(STATE_FILE))?;
+    let version = serde_json::from_slice::<Version>(&bytes)?.version;
+    if version != CHECKPOINT_VERSION {
+        return Err(Error::Format(format!(
+            "Checkpoint version {} is {} than the supported version {}, it can't be resumed",
+            version,
+            if version < CHECKPOINT_VERSION {
+                "older"
+            } else {
+                "newer"
+            },
+            CHECKPOINT_VERSION
+        )));
+    }
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::activation::Activation;
+    use crate::math::loss_functions::CrossEntropy;
+    use crate::nn::initializer::Initializer;
+    use crate::nn::optimizer::{Adam, Momentum};
+
+    fn train_step(nn: &mut Network, x: &Matrix, y: &Matrix) {
+        let mut x = x.clone();
+        nn.feed_forward(&x, y).unwrap();
+        nn.calc_gradients(&mut x, y).unwrap();
+        nn.update_gradients(x.cols).unwrap();
+    }
+
+    #[test]
+    fn test_resume_continues_identically() {
+        let mut nn = Network::new(Adam::new(0.01), CrossEntropy);
+        nn.set_seed(1);
+        nn.add_inp_layer(4, 3, Activation::Tanh, Initializer::XavierUniform)
+            .unwrap();
+        nn.add_layer(2, Activation::Softmax, Initializer::XavierUniform)
+            .unwrap();
+        let x = Matrix::new(3, 2, vec![0.1, -0.3, 0.5, 0.2, -0.7, 0.9]);
+        let y = Matrix::new(2, 2, vec![1.0, 0.0, 0.0, 1.0]);
+        train_step(&mut nn, &x, &y);
+
+        let dir = std::env::temp_dir().join("nn_scratch_checkpoint_test");
+        let progress = Progress {
+            epoch: 3,
+            batch: 7,
+            seed: 42,
+            loss: 3.5,
+            correct: 20,
+            seen: 56,
+        };
+        save(&dir, &nn, progress).unwrap();
+        assert!(exists(&dir));
+        let (mut resumed, resumed_progress) = resume_from(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(resumed_progress, progress);
+        train_step(&mut nn, &x, &y);
+        train_step(&mut resumed, &x, &y);
+        assert_eq!(
+            nn.predict(&x).unwrap().scores.data(),
+            resumed.predict(&x).unwrap().scores.data()
+        );
+    }
+
+    #[test]
+    fn test_other_versions_are_rejected() {
+        let mut nn = Network::new(Adam::new(0.01), CrossEntropy);
+        nn.add_inp_layer(2, 2, Activation::Softmax, Initializer::XavierUniform)
+            .unwrap();
+        let dir = std::env::temp_dir().join("nn_scratch_checkpoint_version_test");
+        save(&dir, &nn, Progress::default()).unwrap();
+
+        let path = dir.join(STATE_FILE);
+        let state: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        for version in [CHECKPOINT_VERSION - 1, CHECKPOINT_VERSION + 1] {
+            let mut old = state.clone();
+            old["version"] = version.into();
+            if version < CHECKPOINT_VERSION {
+                old.as_object_mut().unwrap().remove("samples");
+            }
+            fs::write(&path, old.to_string()).unwrap();
+            assert!(matches!(resume_from(&dir), Err(Error::Format(_))));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mismatched_optimizer_state_is_rejected() {
+        let mut nn = Network::new(Momentum::new(0.1, 0.9), CrossEntropy);
+        nn.add_inp_layer(4, 3, Activation::Tanh, Initializer::XavierUniform)
+            .unwrap();
+        nn.add_layer(2, Activation::Softmax, Initializer::XavierUniform)
+            .unwrap();
+        let x = Matrix::new(3, 1, vec![0.1, -0.3, 0.5]);
+        train_step(&mut nn, &x, &Matrix::new(2, 1, vec![1.0, 0.0]));
+        let dir = std::env::temp_dir().join("nn_scratch_checkpoint_optimizer_test");
+        save(&dir, &nn, Progress::default()).unwrap();
+
+        // The velocity of the first bias in place of the one of the first weights
+        let path = dir.join(STATE_FILE);
+        let mut state: serde_json::Value =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let velocity = &mut state["optimizer"]["Momentum"]["velocity"];
+        velocity[0] = velocity[1].clone();
+        fs::write(&path, state.to_string()).unwrap();
+        let result = resume_from(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(result, Err(Error::Format(_))));
+    }
+}
@@ -0,0 +1,336 @@
+use crate::data::dataset::Dataset;
+use crate::data::labels::{label_class, LabelEncoder};
+use crate::data::loader::DataLoader;
+use crate::error::Result;
+use crate::math::matrix::Matrix;
+use crate::nn::perceptron::Network;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+
+const BATCH_SIZE: usize = 256;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ClassMetrics {
+    pub class: usize,
+    // From the network's label encoder, when it has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+    // Samples whose label is this class
+    pub support: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Averages {
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+}
+
+// Results of running a network over a labelled set. Printing it gives a human readable summary,
+// `to_json` the same in JSON.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Report {
+    pub samples: usize,
+    // Loss averaged over every sample
+    pub loss: f64,
+    pub accuracy: f64,
+    pub top_k: usize,
+    // Fraction of samples whose label is among the `top_k` most likely classes
+    pub top_k_accuracy: f64,
+    // confusion[actual][predicted] sample counts
+    pub confusion: Vec<Vec<usize>>,
+    pub classes: Vec<ClassMetrics>,
+    // Unweighted mean over the classes
+    pub macro_average: Averages,
+    // Mean over the classes weighted by their support
+    pub weighted_average: Averages,
+}
+
+// Runs `network` over `samples` in batches without touching its training state.
+pub fn evaluate<D: Dataset + ?Sized>(
+    network: &Network,
+    samples: &D,
+    top_k: usize,
+) -> Result<Report> {
+    let mut evaluation = Evaluation::default();
+    for batch in DataLoader::new(samples, BATCH_SIZE)?.epoch(0) {
+        let (x, y) = batch?;
+        let (loss, prediction) = network.evaluate(&x, &y)?;
+        evaluation.add(&prediction.scores, &y, loss * x.cols as f64);
+    }
+    Ok(evaluation.report(top_k, network.labels()))
+}
+
+// Class scores and labels gathered batch by batch
+#[derive(Default)]
+struct Evaluation {
+    loss: f64,
+    scores: Vec<Vec<f64>>,
+    labels: Vec<usize>,
+}
+
+impl Evaluation {
+    fn add(&mut self, probabilities: &Matrix, y: &Matrix, loss: f64) {
+        self.loss += loss;
+        for j in 0..y.cols {
+            self.scores.push(class_scores(probabilities, j));
+            self.labels.push(label_class(y, j));
+        }
+    }
+
+    fn report(&self, top_k: usize, labels: Option<&LabelEncoder>) -> Report {
+        let n = self.labels.len();
+        let class_count = self
+            .scores
+            .first()
+            .map_or(0, |s| s.len())
+            .max(self.labels.iter().map(|&l| l + 1).max().unwrap_or(0));
+
+        let mut confusion = vec![vec![0; class_count]; class_count];
+        let mut top_k_correct = 0;
+        for (scores, &label) in self.scores.iter().zip(self.labels.iter()) {
+            let ranked = rank(scores);
+            confusion[label][ranked[0]] += 1;
+            if ranked.iter().take(top_k).any(|&c| c == label) {
+                top_k_correct += 1;
+            }
+        }
+
+        let classes: Vec<ClassMetrics> = (0..class_count)
+            .map(|c| {
+                let true_positives = confusion[c][c] as f64;
+                let predicted: usize = confusion.iter().map(|row| row[c]).sum();
+                let support: usize = confusion[c].iter().sum();
+                let precision = ratio(true_positives, predicted as f64);
+                let recall = ratio(true_positives, support as f64);
+                ClassMetrics {
+                    class: c,
+                    name: labels.and_then(|l| l.name(c)).map(|n| n.to_string()),
+                    precision,
+                    recall,
+                    f1: ratio(2.0 * precision * recall, precision + recall),
+                    support,
+                }
+            })
+            .collect();
+
+        let correct: usize = (0..class_count).map(|c| confusion[c][c]).sum();
+        Report {
+            samples: n,
+            loss: ratio(self.loss, n as f64),
+            accuracy: ratio(correct as f64, n as f64),
+            top_k,
+            top_k_accuracy: ratio(top_k_correct as f64, n as f64),
+            macro_average: average(&classes, |_| 1.0),
+            weighted_average: average(&classes, |m| m.support as f64),
+            confusion,
+            classes,
+        }
+    }
+}
+
+impl Report {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Samples: {} Loss: {:.5}", self.samples, self.loss)?;
+        writeln!(
+            f,
+            "Accuracy: {:.4} Top-{} Accuracy: {:.4}",
+            self.accuracy, self.top_k, self.top_k_accuracy
+        )?;
+
+        writeln!(f, "\nConfusion matrix (rows: actual, columns: predicted)")?;
+        let width = self
+            .confusion
+            .iter()
+            .flatten()
+            .max()
+            .map_or(1, |m| m.to_string().len())
+            .max(self.confusion.len().to_string().len());
+        write!(f, "{:>w$}", "", w = width)?;
+        for c in 0..self.confusion.len() {
+            write!(f, " {:>w$}", c, w = width)?;
+        }
+        writeln!(f)?;
+        for (c, row) in self.confusion.iter().enumerate() {
+            write!(f, "{:>w$}", c, w = width)?;
+            for count in row {
+                write!(f, " {:>w$}", count, w = width)?;
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(
+            f,
+            "\n{:>12} {:>9} {:>9} {:>9} {:>9}",
+            "Class", "Precision", "Recall", "F1", "Support"
+        )?;
+        for m in &self.classes {
+            writeln!(
+                f,
+                "{:>12} {:>9.4} {:>9.4} {:>9.4} {:>9}",
+                m.name.clone().unwrap_or_else(|| m.class.to_string()),
+                m.precision,
+                m.recall,
+                m.f1,
+                m.support
+            )?;
+        }
+        for (name, a) in [
+            ("Macro avg", &self.macro_average),
+            ("Weighted avg", &self.weighted_average),
+        ] {
+            writeln!(
+                f,
+                "{:>12} {:>9.4} {:>9.4} {:>9.4} {:>9}",
+                name, a.precision, a.recall, a.f1, self.samples
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Scores of every class for column `j`. A single output is the probability of class 1.
+fn class_scores(probabilities: &Matrix, j: usize) -> Vec<f64> {
+    match probabilities.rows {
+        1 => {
+            let p = probabilities.get(0, j);
+            vec![1.0 - p, p]
+        }
+        rows => (0..rows).map(|i| probabilities.get(i, j)).collect(),
+    }
+}
+
+// Classes from the most to the least likely
+fn rank(scores: &[f64]) -> Vec<usize> {
+    let mut ranked: Vec<usize> = (0..scores.len()).collect();
+    ranked.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+    ranked
+}
+
+fn average(classes: &[ClassMetrics], weight: impl Fn(&ClassMetrics) -> f64) -> Averages {
+    let total: f64 = classes.iter().map(&weight).sum();
+    let mean = |metric: fn(&ClassMetrics) -> f64| {
+        ratio(classes.iter().map(|m| weight(m) * metric(m)).sum(), total)
+    };
+    Averages {
+        precision: mean(|m| m.precision),
+        recall: mean(|m| m.recall),
+        f1: mean(|m| m.f1),
+    }
+}
+
+// 0 for an empty denominator, e.g. the precision of a class that is never predicted
+#[inline]
+fn ratio(numerator: f64, denominator: f64) -> f64 {
+    if denominator == 0.0 {
+        0.0
+    } else {
+        numerator / denominator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::dataset::Samples;
+    use crate::data::definition::FASHION_MNIST;
+    use crate::math::activation::Activation;
+    use crate::math::loss_functions::CrossEntropy;
+    use crate::nn::initializer::Initializer;
+    use crate::nn::optimizer::Sgd;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-12
+    }
+
+    // Three classes, 5 samples: labels 0 0 1 1 2, predicted 0 1 1 1 0
+    fn report() -> Report {
+        let probabilities = Matrix::new(
+            3,
+            5,
+            vec![
+                0.7, 0.2, 0.1, 0.3, 0.5, //
+                0.2, 0.5, 0.8, 0.6, 0.1, //
+                0.1, 0.3, 0.1, 0.1, 0.4,
+            ],
+        );
+        let labels = Matrix::new(1, 5, vec![0.0, 0.0, 1.0, 1.0, 2.0]);
+        let mut evaluation = Evaluation::default();
+        evaluation.add(&probabilities, &labels, 2.5);
+        evaluation.report(2, None)
+    }
+
+    #[test]
+    fn test_confusion_and_accuracy() {
+        let r = report();
+        assert_eq!(r.samples, 5);
+        assert!(close(r.loss, 0.5));
+        assert_eq!(
+            r.confusion,
+            vec![vec![1, 1, 0], vec![0, 2, 0], vec![1, 0, 0]]
+        );
+        assert!(close(r.accuracy, 0.6));
+        // Sample 1 ranks its label 0 last, the other misses are second choices
+        assert!(close(r.top_k_accuracy, 0.8));
+    }
+
+    #[test]
+    fn test_per_class_metrics() {
+        let r = report();
+        let c = &r.classes;
+        assert!(close(c[0].precision, 0.5) && close(c[0].recall, 0.5) && close(c[0].f1, 0.5));
+        assert!(close(c[1].precision, 2.0 / 3.0) && close(c[1].recall, 1.0));
+        assert!(close(c[1].f1, 0.8));
+        // Never predicted, no division by zero
+        assert_eq!((c[2].precision, c[2].recall, c[2].f1), (0.0, 0.0, 0.0));
+        assert_eq!(
+            c.iter().map(|m| m.support).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+
+        assert!(close(r.macro_average.recall, 0.5));
+        assert!(close(r.weighted_average.recall, 0.6));
+        assert!(close(r.macro_average.f1, 1.3 / 3.0));
+    }
+
+    #[test]
+    fn test_evaluate_network() {
+        let mut nn = Network::new(Sgd::new(0.1), CrossEntropy);
+        nn.set_seed(3);
+        nn.add_inp_layer(10, 4, Activation::Softmax, Initializer::XavierUniform)
+            .unwrap();
+        nn.set_labels(LabelEncoder::from_definition(&FASHION_MNIST))
+            .unwrap();
+        let inputs: Vec<Matrix> = (0..20)
+            .map(|i| Matrix::new(4, 1, vec![i as f64 * 0.1, -0.2, 0.3, 1.0]))
+            .collect();
+        let labels: Vec<Matrix> = (0..20)
+            .map(|i| {
+                let mut one_hot = vec![0.0; 10];
+                one_hot[i % 10] = 1.0;
+                Matrix::new(10, 1, one_hot)
+            })
+            .collect();
+
+        let r = evaluate(&nn, &Samples::new(&inputs, &labels).unwrap(), 5).unwrap();
+
+        assert_eq!(r.samples, 20);
+        assert_eq!(r.confusion.len(), 10);
+        assert_eq!(r.confusion.iter().flatten().sum::<usize>(), 20);
+        assert!(r.top_k_accuracy >= r.accuracy);
+        let json: serde_json::Value = serde_json::from_str(&r.to_json()).unwrap();
+        assert_eq!(json["classes"].as_array().unwrap().len(), 10);
+        assert!(r.to_string().contains("Weighted avg"));
+        assert!(r.to_string().contains("Ankle boot"));
+        assert_eq!(json["classes"][1]["name"], "Trouser");
+    }
+}
@@ -0,0 +1,240 @@
+use crate::error::{Error, Result};
+use crate::math::matrix::Matrix;
+use crate::math::random::standard_normal;
+use rand::distr::{Distribution, Uniform};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// How a (neurons x inputs) parameter matrix is filled. Fan-in is the number of inputs (columns),
+// fan-out the number of neurons (rows).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Initializer {
+    // Glorot: U(-l, l) with l = sqrt(6 / (fan_in + fan_out)), suited to tanh and sigmoid
+    XavierUniform,
+    // Glorot: N(0, 2 / (fan_in + fan_out))
+    XavierNormal,
+    // Kaiming: U(-l, l) with l = sqrt(6 / fan_in), suited to the ReLU family
+    HeUniform,
+    // Kaiming: N(0, 2 / fan_in)
+    HeNormal,
+    // U(-l, l) with l = sqrt(3 / fan_in), suited to SELU
+    LecunUniform,
+    // N(0, 1 / fan_in)
+    LecunNormal,
+    // Orthonormal rows or columns, whichever there are fewer of, scaled by `gain`
+    Orthogonal { gain: f64 },
+    Uniform { limit: f64 },
+    Normal { std: f64 },
+    Constant(f64),
+    Zeros,
+}
+
+// Initialisers of a layer's weights and bias. An `Initializer` on its own initialises the
+// weights and leaves the bias at zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Init {
+    pub weights: Initializer,
+    pub bias: Initializer,
+}
+
+impl From<Initializer> for Init {
+    fn from(weights: Initializer) -> Self {
+        Init {
+            weights,
+            bias: Initializer::Zeros,
+        }
+    }
+}
+
+impl Initializer {
+    // Rejects parameters `init` can't draw from: a negative or non-finite limit or standard deviation,
+    // or a non-finite constant or gain.
+    pub fn check(&self) -> Result<()> {
+        let valid = match *self {
+            Initializer::Uniform { limit: spread } | Initializer::Normal { std: spread } => {
+                spread.is_finite() && spread >= 0.0
+            }
+            Initializer::Orthogonal { gain: value } | Initializer::Constant(value) => {
+                value.is_finite()
+            }
+            _ => true,
+        };
+        match valid {
+            true => Ok(()),
+            false => Err(Error::InvalidArgument(format!(
+                "Invalid initializer parameters {:?}",
+                self
+            ))),
+        }
+    }
+
+    // Expects parameters accepted by `check`
+    pub fn init(&self, rows: usize, cols: usize, rng: &mut impl Rng) -> Matrix {
+        let (fan_in, fan_out) = (cols.max(1) as f64, rows.max(1) as f64);
+        let n = rows * cols;
+        let data = match *self {
+            Initializer::XavierUniform => uniform(n, (6.0 / (fan_in + fan_out)).sqrt(), rng),
+            Initializer::XavierNormal => normal(n, (2.0 / (fan_in + fan_out)).sqrt(), rng),
+            Initializer::HeUniform => uniform(n, (6.0 / fan_in).sqrt(), rng),
+            Initializer::HeNormal => normal(n, (2.0 / fan_in).sqrt(), rng),
+            Initializer::LecunUniform => uniform(n, (3.0 / fan_in).sqrt(), rng),
+            Initializer::LecunNormal => normal(n, (1.0 / fan_in).sqrt(), rng),
+            Initializer::Orthogonal { gain } => orthogonal(rows, cols, gain, rng),
+            Initializer::Uniform { limit } => uniform(n, limit, rng),
+            Initializer::Normal { std } => normal(n, std, rng),
+            Initializer::Constant(c) => vec![c; n],
+            Initializer::Zeros => vec![0.0; n],
+        };
+        Matrix::new(rows, cols, data)
+    }
+}
+
+fn uniform(n: usize, limit: f64, rng: &mut impl Rng) -> Vec<f64> {
+    if limit == 0.0 {
+        return vec![0.0; n];
+    }
+    let distribution = Uniform::try_from(-limit..limit).unwrap();
+    (0..n).map(|_| distribution.sample(rng)).collect()
+}
+
+fn normal(n: usize, std: f64, rng: &mut impl Rng) -> Vec<f64> {
+    (0..n).map(|_| std * standard_normal(rng)).collect()
+}
+
+// Gram-Schmidt on Gaussian vectors. A (rows x cols) matrix gets orthonormal columns when
+// rows >= cols and orthonormal rows otherwise.
+fn orthogonal(rows: usize, cols: usize, gain: f64, rng: &mut impl Rng) -> Vec<f64> {
+    let (count, len) = if rows >= cols {
+        (cols, rows)
+    } else {
+        (rows, cols)
+    };
+    let mut vectors: Vec<Vec<f64>> = vec![];
+    while vectors.len() < count {
+        let mut v = normal(len, 1.0, rng);
+        for u in &vectors {
+            let d: f64 = v.iter().zip(u).map(|(a, b)| a * b).sum();
+            v.iter_mut().zip(u).for_each(|(a, b)| *a -= d * b);
+        }
+        let norm = v.iter().map(|a| a * a).sum::<f64>().sqrt();
+        // A nearly dependent draw is retried rather than normalised
+        if norm > 1e-8 {
+            vectors.push(v.into_iter().map(|a| a / norm).collect());
+        }
+    }
+
+    let mut data = vec![0.0; rows * cols];
+    for i in 0..rows {
+        for j in 0..cols {
+            let value = if rows >= cols {
+                vectors[j][i]
+            } else {
+                vectors[i][j]
+            };
+            data[i * cols + j] = gain * value;
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::random::SeededRng;
+
+    fn stats(m: &Matrix) -> (f64, f64) {
+        let n = m.data().len() as f64;
+        let mean = m.data().iter().sum::<f64>() / n;
+        let var = m.data().iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+        (mean, var)
+    }
+
+    #[test]
+    fn test_variance_scaling() {
+        let (rows, cols) = (200, 300);
+        let cases = [
+            (Initializer::XavierUniform, 2.0 / 500.0),
+            (Initializer::XavierNormal, 2.0 / 500.0),
+            (Initializer::HeUniform, 2.0 / 300.0),
+            (Initializer::HeNormal, 2.0 / 300.0),
+            (Initializer::LecunUniform, 1.0 / 300.0),
+            (Initializer::LecunNormal, 1.0 / 300.0),
+        ];
+        for (init, expected) in cases {
+            let (mean, var) = stats(&init.init(rows, cols, &mut SeededRng::new(0)));
+            assert!(mean.abs() < 0.01, "{:?} mean {}", init, mean);
+            assert!(
+                (var / expected - 1.0).abs() < 0.05,
+                "{:?} variance {}",
+                init,
+                var
+            );
+        }
+    }
+
+    #[test]
+    fn test_uniform_limits() {
+        let limit = (6.0f64 / 10.0).sqrt();
+        let m = Initializer::HeUniform.init(50, 10, &mut SeededRng::new(0));
+        assert!(m.data().iter().all(|v| v.abs() <= limit));
+    }
+
+    #[test]
+    fn test_orthogonal() {
+        for (rows, cols) in [(6, 4), (4, 6), (5, 5)] {
+            let m = Initializer::Orthogonal { gain: 2.0 }.init(rows, cols, &mut SeededRng::new(0));
+            let mut t = m.clone();
+            t.transpose();
+            // The smaller Gram matrix is 4 * identity
+            let gram = if rows >= cols {
+                t.dot(&m).unwrap()
+            } else {
+                m.dot(&t).unwrap()
+            };
+            for i in 0..gram.rows {
+                for j in 0..gram.cols {
+                    let expected = if i == j { 4.0 } else { 0.0 };
+                    assert!((gram.get(i, j) - expected).abs() < 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_seeded_init_is_reproducible() {
+        let init = |seed| Initializer::XavierNormal.init(4, 3, &mut SeededRng::new(seed));
+        assert_eq!(init(3).data(), init(3).data());
+        assert_ne!(init(3).data(), init(4).data());
+    }
+
+    #[test]
+    fn test_constant_and_zeros() {
+        assert!(Initializer::Constant(0.1)
+            .init(3, 2, &mut SeededRng::new(0))
+            .data()
+            .iter()
+            .all(|&v| v == 0.1));
+        assert!(Initializer::Zeros
+            .init(3, 2, &mut SeededRng::new(0))
+            .data()
+            .iter()
+            .all(|&v| v == 0.0));
+    }
+
+    #[test]
+    fn test_check_rejects_bad_parameters() {
+        let rejected = [
+            Initializer::Uniform { limit: -0.5 },
+            Initializer::Uniform { limit: f64::NAN },
+            Initializer::Normal { std: f64::INFINITY },
+            Initializer::Normal { std: -1.0 },
+            Initializer::Orthogonal { gain: f64::NAN },
+            Initializer::Constant(f64::NEG_INFINITY),
+        ];
+        for init in rejected {
+            assert!(matches!(init.check(), Err(Error::InvalidArgument(_))));
+        }
+        assert!(Initializer::Uniform { limit: 0.0 }.check().is_ok());
+        assert!(Initializer::Constant(-2.0).check().is_ok());
+    }
+}
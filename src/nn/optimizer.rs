@@ -0,0 +1,440 @@
+use crate::error::{Error, Result};
+use crate::math::matrix::Matrix;
+use serde::{Deserialize, Serialize};
+
+// Every trainable parameter (each layer's weights and bias) is identified by an `id`, so stateful
+// optimizers can keep their velocity / moment estimates for it between updates.
+pub trait Optimizer: Send + Sync {
+    // Called once per update, before any parameter is updated.
+    fn begin_step(&mut self) {}
+
+    // Updates `param` in place using its gradient averaged over the batch.
+    fn update(&mut self, id: usize, param: &mut Matrix, grad: &Matrix);
+
+    // Serialisable copy of the hyperparameters and per-parameter state for checkpoints,
+    // `None` for optimizers that can't be saved.
+    fn to_saved(&self) -> Option<SavedOptimizer> {
+        None
+    }
+}
+
+// Every built-in optimizer along with its state, as stored in checkpoints.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum SavedOptimizer {
+    Sgd(Sgd),
+    Momentum(Momentum),
+    Nesterov(Nesterov),
+    RmsProp(RmsProp),
+    Adagrad(Adagrad),
+    Adam(Adam),
+    AdamW(AdamW),
+}
+
+impl SavedOptimizer {
+    pub fn restore(self) -> Box<dyn Optimizer> {
+        match self {
+            SavedOptimizer::Sgd(o) => Box::new(o),
+            SavedOptimizer::Momentum(o) => Box::new(o),
+            SavedOptimizer::Nesterov(o) => Box::new(o),
+            SavedOptimizer::RmsProp(o) => Box::new(o),
+            SavedOptimizer::Adagrad(o) => Box::new(o),
+            SavedOptimizer::Adam(o) => Box::new(o),
+            SavedOptimizer::AdamW(o) => Box::new(o),
+        }
+    }
+
+    // Checks every saved state buffer against `shapes`, the (rows, cols) of the parameter with the
+    // same id, so a checkpoint that doesn't fit the network is rejected before the first update.
+    pub fn check_shapes(&self, shapes: &[(usize, usize)]) -> Result<()> {
+        let states: Vec<&[Option<Matrix>]> = match self {
+            SavedOptimizer::Sgd(_) => vec![],
+            SavedOptimizer::Momentum(o) => vec![&o.velocity],
+            SavedOptimizer::Nesterov(o) => vec![&o.velocity],
+            SavedOptimizer::RmsProp(o) => vec![&o.square_avg],
+            SavedOptimizer::Adagrad(o) => vec![&o.square_sum],
+            SavedOptimizer::Adam(o) => vec![&o.m, &o.v],
+            SavedOptimizer::AdamW(o) => vec![&o.adam.m, &o.adam.v],
+        };
+        for slots in states {
+            if slots.len() > shapes.len() {
+                return Err(Error::Format(format!(
+                    "Optimizer state for {} parameters but the network has {}",
+                    slots.len(),
+                    shapes.len()
+                )));
+            }
+            for (id, slot) in slots.iter().enumerate() {
+                if let Some(m) = slot.as_ref().filter(|m| (m.rows, m.cols) != shapes[id]) {
+                    return Err(Error::Format(format!(
+                        "Optimizer state of parameter {} is ({} x {}) but the parameter is {:?}",
+                        id, m.rows, m.cols, shapes[id]
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Returns the state buffer of parameter `id`, creating a zeroed one shaped like `like` on first use.
+fn slot<'a>(states: &'a mut Vec<Option<Matrix>>, id: usize, like: &Matrix) -> &'a mut Matrix {
+    if states.len() <= id {
+        states.resize(id + 1, None);
+    }
+    states[id].get_or_insert_with(|| Matrix::repeat(like.rows, like.cols, 0.0))
+}
+
+// Plain gradient descent: p = p - lr * g
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sgd {
+    pub learning_rate: f64,
+}
+
+impl Sgd {
+    pub fn new(learning_rate: f64) -> Self {
+        Sgd { learning_rate }
+    }
+}
+
+impl Optimizer for Sgd {
+    fn update(&mut self, _id: usize, param: &mut Matrix, grad: &Matrix) {
+        let lr = self.learning_rate;
+        *param = param.zip_map(grad, |p, g| p - lr * g);
+    }
+
+    fn to_saved(&self) -> Option<SavedOptimizer> {
+        Some(SavedOptimizer::Sgd(self.clone()))
+    }
+}
+
+// Heavy ball momentum: v = mu * v + g, p = p - lr * v
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Momentum {
+    pub learning_rate: f64,
+    pub momentum: f64,
+    velocity: Vec<Option<Matrix>>,
+}
+
+impl Momentum {
+    pub fn new(learning_rate: f64, momentum: f64) -> Self {
+        Momentum {
+            learning_rate,
+            momentum,
+            velocity: vec![],
+        }
+    }
+}
+
+impl Optimizer for Momentum {
+    fn update(&mut self, id: usize, param: &mut Matrix, grad: &Matrix) {
+        let (lr, mu) = (self.learning_rate, self.momentum);
+        let v = slot(&mut self.velocity, id, grad);
+        *v = v.zip_map(grad, |v, g| mu * v + g);
+        *param = param.zip_map(v, |p, v| p - lr * v);
+    }
+
+    fn to_saved(&self) -> Option<SavedOptimizer> {
+        Some(SavedOptimizer::Momentum(self.clone()))
+    }
+}
+
+// Nesterov accelerated gradient, evaluated as a look-ahead on the updated velocity:
+// v = mu * v + g, p = p - lr * (g + mu * v)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Nesterov {
+    pub learning_rate: f64,
+    pub momentum: f64,
+    velocity: Vec<Option<Matrix>>,
+}
+
+impl Nesterov {
+    pub fn new(learning_rate: f64, momentum: f64) -> Self {
+        Nesterov {
+            learning_rate,
+            momentum,
+            velocity: vec![],
+        }
+    }
+}
+
+impl Optimizer for Nesterov {
+    fn update(&mut self, id: usize, param: &mut Matrix, grad: &Matrix) {
+        let (lr, mu) = (self.learning_rate, self.momentum);
+        let v = slot(&mut self.velocity, id, grad);
+        *v = v.zip_map(grad, |v, g| mu * v + g);
+        let step = grad.zip_map(v, |g, v| lr * (g + mu * v));
+        *param = &*param - &step;
+    }
+
+    fn to_saved(&self) -> Option<SavedOptimizer> {
+        Some(SavedOptimizer::Nesterov(self.clone()))
+    }
+}
+
+// Divides the learning rate by a running average of squared gradients:
+// s = rho * s + (1 - rho) * g^2, p = p - lr * g / (sqrt(s) + eps)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RmsProp {
+    pub learning_rate: f64,
+    pub decay: f64,
+    pub epsilon: f64,
+    square_avg: Vec<Option<Matrix>>,
+}
+
+impl RmsProp {
+    pub fn new(learning_rate: f64) -> Self {
+        RmsProp {
+            learning_rate,
+            decay: 0.9,
+            epsilon: 1e-8,
+            square_avg: vec![],
+        }
+    }
+}
+
+impl Optimizer for RmsProp {
+    fn update(&mut self, id: usize, param: &mut Matrix, grad: &Matrix) {
+        let (lr, rho, eps) = (self.learning_rate, self.decay, self.epsilon);
+        let s = slot(&mut self.square_avg, id, grad);
+        *s = s.zip_map(grad, |s, g| rho * s + (1.0 - rho) * g * g);
+        let step = grad.zip_map(s, |g, s| lr * g / (s.sqrt() + eps));
+        *param = &*param - &step;
+    }
+
+    fn to_saved(&self) -> Option<SavedOptimizer> {
+        Some(SavedOptimizer::RmsProp(self.clone()))
+    }
+}
+
+// Divides the learning rate by the root of all squared gradients seen so far:
+// s = s + g^2, p = p - lr * g / (sqrt(s) + eps)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Adagrad {
+    pub learning_rate: f64,
+    pub epsilon: f64,
+    square_sum: Vec<Option<Matrix>>,
+}
+
+impl Adagrad {
+    pub fn new(learning_rate: f64) -> Self {
+        Adagrad {
+            learning_rate,
+            epsilon: 1e-8,
+            square_sum: vec![],
+        }
+    }
+}
+
+impl Optimizer for Adagrad {
+    fn update(&mut self, id: usize, param: &mut Matrix, grad: &Matrix) {
+        let (lr, eps) = (self.learning_rate, self.epsilon);
+        let s = slot(&mut self.square_sum, id, grad);
+        *s = s.zip_map(grad, |s, g| s + g * g);
+        let step = grad.zip_map(s, |g, s| lr * g / (s.sqrt() + eps));
+        *param = &*param - &step;
+    }
+
+    fn to_saved(&self) -> Option<SavedOptimizer> {
+        Some(SavedOptimizer::Adagrad(self.clone()))
+    }
+}
+
+// Bias corrected first and second moment estimates:
+// m = b1 * m + (1 - b1) * g, v = b2 * v + (1 - b2) * g^2
+// p = p - lr * m_hat / (sqrt(v_hat) + eps)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Adam {
+    pub learning_rate: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub epsilon: f64,
+    t: i32,
+    m: Vec<Option<Matrix>>,
+    v: Vec<Option<Matrix>>,
+}
+
+impl Adam {
+    pub fn new(learning_rate: f64) -> Self {
+        Adam {
+            learning_rate,
+            beta1: 0.9,
+            beta2: 0.999,
+            epsilon: 1e-8,
+            t: 0,
+            m: vec![],
+            v: vec![],
+        }
+    }
+
+    fn step(&mut self, id: usize, grad: &Matrix) -> Matrix {
+        let (lr, b1, b2, eps) = (self.learning_rate, self.beta1, self.beta2, self.epsilon);
+        let m_correction = 1.0 - b1.powi(self.t);
+        let v_correction = 1.0 - b2.powi(self.t);
+
+        let m = slot(&mut self.m, id, grad);
+        *m = m.zip_map(grad, |m, g| b1 * m + (1.0 - b1) * g);
+        let v = slot(&mut self.v, id, grad);
+        *v = v.zip_map(grad, |v, g| b2 * v + (1.0 - b2) * g * g);
+
+        let m_hat = self.m[id].as_ref().unwrap().map(|m| m / m_correction);
+        m_hat.zip_map(self.v[id].as_ref().unwrap(), |m, v| {
+            lr * m / ((v / v_correction).sqrt() + eps)
+        })
+    }
+}
+
+impl Optimizer for Adam {
+    fn begin_step(&mut self) {
+        self.t += 1;
+    }
+
+    fn update(&mut self, id: usize, param: &mut Matrix, grad: &Matrix) {
+        let step = self.step(id, grad);
+        *param = &*param - &step;
+    }
+
+    fn to_saved(&self) -> Option<SavedOptimizer> {
+        Some(SavedOptimizer::Adam(self.clone()))
+    }
+}
+
+// Adam with decoupled weight decay, parameters shrink by lr * weight_decay before the Adam step.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AdamW {
+    pub weight_decay: f64,
+    adam: Adam,
+}
+
+impl AdamW {
+    pub fn new(learning_rate: f64, weight_decay: f64) -> Self {
+        AdamW {
+            weight_decay,
+            adam: Adam::new(learning_rate),
+        }
+    }
+}
+
+impl Optimizer for AdamW {
+    fn begin_step(&mut self) {
+        self.adam.begin_step();
+    }
+
+    fn update(&mut self, id: usize, param: &mut Matrix, grad: &Matrix) {
+        let decay = 1.0 - self.adam.learning_rate * self.weight_decay;
+        let step = self.adam.step(id, grad);
+        *param = param.zip_map(&step, |p, s| p * decay - s);
+    }
+
+    fn to_saved(&self) -> Option<SavedOptimizer> {
+        Some(SavedOptimizer::AdamW(self.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_approx_eq(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "left = {}, right = {}", a, b);
+    }
+
+    // Minimises f(p) = p^2 starting at p = 1.0, returning the final p
+    fn minimise(optimizer: &mut dyn Optimizer, steps: usize) -> f64 {
+        let mut p = Matrix::new(1, 1, vec![1.0]);
+        for _ in 0..steps {
+            let g = p.map(|p| 2.0 * p);
+            optimizer.begin_step();
+            optimizer.update(0, &mut p, &g);
+        }
+        p.data()[0]
+    }
+
+    #[test]
+    fn test_sgd_step() {
+        let mut p = Matrix::new(1, 2, vec![1.0, -1.0]);
+        let g = Matrix::new(1, 2, vec![0.5, -0.5]);
+        Sgd::new(0.1).update(0, &mut p, &g);
+        assert_approx_eq(p.data()[0], 0.95);
+        assert_approx_eq(p.data()[1], -0.95);
+    }
+
+    #[test]
+    fn test_momentum_accumulates_velocity() {
+        let mut opt = Momentum::new(0.1, 0.9);
+        let mut p = Matrix::new(1, 1, vec![0.0]);
+        let g = Matrix::new(1, 1, vec![1.0]);
+        opt.update(0, &mut p, &g);
+        assert_approx_eq(p.data()[0], -0.1);
+        opt.update(0, &mut p, &g);
+        // v = 0.9 * 1 + 1 = 1.9
+        assert_approx_eq(p.data()[0], -0.29);
+    }
+
+    #[test]
+    fn test_state_is_kept_per_parameter() {
+        let mut opt = Momentum::new(0.1, 0.9);
+        let mut a = Matrix::new(1, 1, vec![0.0]);
+        let mut b = Matrix::new(2, 1, vec![0.0, 0.0]);
+        opt.update(0, &mut a, &Matrix::new(1, 1, vec![1.0]));
+        opt.update(1, &mut b, &Matrix::new(2, 1, vec![1.0, 1.0]));
+        assert_approx_eq(a.data()[0], -0.1);
+        assert_approx_eq(b.data()[1], -0.1);
+    }
+
+    #[test]
+    fn test_adam_first_step_is_learning_rate() {
+        // Bias correction makes the first Adam step lr * sign(g)
+        let mut opt = Adam::new(0.01);
+        let mut p = Matrix::new(1, 2, vec![1.0, 1.0]);
+        let g = Matrix::new(1, 2, vec![3.0, -0.2]);
+        opt.begin_step();
+        opt.update(0, &mut p, &g);
+        assert_approx_eq(p.data()[0], 0.99);
+        assert_approx_eq(p.data()[1], 1.01);
+    }
+
+    #[test]
+    fn test_adamw_decays_weights() {
+        let mut opt = AdamW::new(0.1, 0.5);
+        let mut p = Matrix::new(1, 1, vec![2.0]);
+        opt.begin_step();
+        opt.update(0, &mut p, &Matrix::new(1, 1, vec![0.0]));
+        assert_approx_eq(p.data()[0], 2.0 * (1.0 - 0.1 * 0.5));
+    }
+
+    #[test]
+    fn test_all_optimizers_converge() {
+        let optimizers: Vec<Box<dyn Optimizer>> = vec![
+            Box::new(Sgd::new(0.1)),
+            Box::new(Momentum::new(0.05, 0.9)),
+            Box::new(Nesterov::new(0.05, 0.9)),
+            Box::new(RmsProp::new(0.01)),
+            Box::new(Adagrad::new(0.5)),
+            Box::new(Adam::new(0.05)),
+            Box::new(AdamW::new(0.05, 0.01)),
+        ];
+        for mut opt in optimizers {
+            assert!(minimise(opt.as_mut(), 500).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn test_saved_optimizer_keeps_state() {
+        let mut opt = Adam::new(0.01);
+        let mut p = Matrix::new(1, 1, vec![1.0]);
+        let g = Matrix::new(1, 1, vec![0.5]);
+        opt.begin_step();
+        opt.update(0, &mut p, &g);
+
+        let json = serde_json::to_string(&opt.to_saved().unwrap()).unwrap();
+        let saved: SavedOptimizer = serde_json::from_str(&json).unwrap();
+        let mut restored = saved.restore();
+        let mut p_restored = p.clone();
+
+        opt.begin_step();
+        opt.update(0, &mut p, &g);
+        restored.begin_step();
+        restored.update(0, &mut p_restored, &g);
+        assert_eq!(p.data(), p_restored.data());
+    }
+}
@@ -1,10 +1,19 @@
+use crate::data::labels::LabelEncoder;
+use crate::error::{Error, Result};
+use crate::math::activation::{softmax, softmax_backward, Activation};
+use crate::math::loss_functions::Loss;
 use crate::math::matrix::Matrix;
+use crate::math::random::SeededRng;
+use crate::nn::checkpoint::{Progress, TrainingState, CHECKPOINT_VERSION};
+use crate::nn::initializer::Init;
+use crate::nn::optimizer::{Optimizer, Sgd};
+use crate::nn::persistence::{ModelFormat, SavedLayer, SavedModel, FORMAT_VERSION};
+use std::path::Path;
 
 struct Layer {
     weights: Matrix,
     bias: Matrix,
-    activation: fn(&Matrix) -> Matrix,
-    d_activation: Option<fn(&Matrix) -> Matrix>,
+    activation: Activation,
 }
 struct FeedForwardStates {
     pre_activation: Vec<Matrix>,
@@ -14,14 +23,47 @@ struct FeedForwardStates {
 struct Gradients {
     errors: Vec<Matrix>,
     gradients: Vec<Matrix>,
+    // Samples accumulated in the buffers since they were last zeroed
+    samples: usize,
+}
+
+// Output of `Network::predict`, one column of class scores per sample. The scores are probabilities
+// when the output layer gives them (softmax, log-softmax, sigmoid) or the loss takes logits, and the
+// raw outputs otherwise, e.g. the margins of `Hinge` or the values of a regression trained with `Sse`.
+pub struct Prediction {
+    pub scores: Matrix,
 }
 
 pub struct Network {
     layers: Vec<Layer>,
-    learning_rate: f64,
-    loss_fn: fn(&Matrix, &Matrix) -> f64,
+    optimizer: Box<dyn Optimizer>,
+    loss: Box<dyn Loss>,
     feed_forward_states: FeedForwardStates,
     back_prop_states: Gradients,
+    // Draws the initial weights of every added layer
+    rng: SeededRng,
+    // Names of the output classes, saved with the model
+    labels: Option<LabelEncoder>,
+}
+
+impl Prediction {
+    // Most likely class of every sample
+    pub fn classes(&self) -> Vec<usize> {
+        self.top_k(1).into_iter().map(|top| top[0].0).collect()
+    }
+
+    // The `k` most likely classes of every sample with their scores, most likely first.
+    pub fn top_k(&self, k: usize) -> Vec<Vec<(usize, f64)>> {
+        let p = &self.scores;
+        (0..p.cols)
+            .map(|j| {
+                let mut ranked: Vec<(usize, f64)> = (0..p.rows).map(|i| (i, p.get(i, j))).collect();
+                ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+                ranked.truncate(k);
+                ranked
+            })
+            .collect()
+    }
 }
 
 impl Gradients {
@@ -29,7 +71,18 @@ impl Gradients {
         Gradients {
             errors: vec![],
             gradients: vec![],
+            samples: 0,
+        }
+    }
+
+    fn zero(&mut self) {
+        for g in self.gradients.iter_mut() {
+            *g = Matrix::repeat(g.rows, g.cols, 0.0);
+        }
+        for e in self.errors.iter_mut() {
+            *e = Matrix::repeat(e.rows, e.cols, 0.0);
         }
+        self.samples = 0;
     }
 }
 
@@ -47,170 +100,889 @@ impl FeedForwardStates {
 }
 
 impl Network {
-    pub fn new(learning_rate: f64, loss_fn: fn(&Matrix, &Matrix) -> f64) -> Network {
+    pub fn new(optimizer: impl Optimizer + 'static, loss: impl Loss + 'static) -> Network {
         Network {
-            learning_rate,
-            loss_fn,
+            optimizer: Box::new(optimizer),
+            loss: Box::new(loss),
             layers: vec![],
             feed_forward_states: FeedForwardStates::new(),
             back_prop_states: Gradients::new(),
+            rng: SeededRng::from_entropy(),
+            labels: None,
         }
     }
 
-    pub fn add_inp_layer(
-        &mut self,
-        neurons: usize,
-        input_size: usize,
-        activation: fn(&Matrix) -> Matrix,
-        d_activation: Option<fn(&Matrix) -> Matrix>,
-    ) {
-        self.layers.push(Layer {
-            weights: Matrix::uniform(neurons, input_size),
-            activation,
-            bias: Matrix::repeat(neurons, 1, 0.0),
-            d_activation,
-        });
+    // Layers added from now on are initialised from `seed`, call it before adding any layer for a
+    // reproducible network.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = SeededRng::new(seed);
+    }
 
+    pub fn seed(&self) -> u64 {
+        self.rng.seed()
+    }
+
+    // Names the output classes, e.g. `LabelEncoder::from_definition` of the training set. Call it once
+    // the output layer is added, its size must match the class count.
+    pub fn set_labels(&mut self, labels: LabelEncoder) -> Result<()> {
+        let outputs = match self.layers.last() {
+            Some(layer) => layer.weights.rows,
+            None => return Err(Error::State("Network has no layers".to_string())),
+        };
+        if outputs != labels.classes() {
+            return Err(Error::Shape(format!(
+                "{} classes for {} outputs",
+                labels.classes(),
+                outputs
+            )));
+        }
+        self.labels = Some(labels);
+        Ok(())
+    }
+
+    pub fn labels(&self) -> Option<&LabelEncoder> {
+        self.labels.as_ref()
+    }
+
+    pub fn set_optimizer(&mut self, optimizer: impl Optimizer + 'static) {
+        self.optimizer = Box::new(optimizer);
+    }
+
+    fn push_layer(&mut self, layer: Layer) {
+        let (rows, cols) = (layer.weights.rows, layer.weights.cols);
+        self.layers.push(layer);
         self.back_prop_states
             .gradients
-            .push(Matrix::repeat(neurons, input_size, 0.0));
-
+            .push(Matrix::repeat(rows, cols, 0.0));
         self.back_prop_states
             .errors
-            .push(Matrix::repeat(neurons, 1, 0.0));
+            .push(Matrix::repeat(rows, 1, 0.0));
     }
 
-    pub fn add_layer(
+    // The initialiser is either an `Initializer` for the weights, with a zero bias, or an `Init`
+    // choosing both.
+    pub fn add_inp_layer(
         &mut self,
         neurons: usize,
-        activation: fn(&Matrix) -> Matrix,
-        d_activation: Option<fn(&Matrix) -> Matrix>,
-    ) {
-        if self.layers.is_empty() {
-            panic!("Add an input layer before adding hidden layers");
+        input_size: usize,
+        activation: Activation,
+        init: impl Into<Init>,
+    ) -> Result<()> {
+        if neurons == 0 || input_size == 0 {
+            return Err(Error::InvalidArgument(
+                "A layer needs at least one neuron and one input".to_string(),
+            ));
         }
 
-        let prev_rows = self.layers.last().unwrap().weights.rows;
-        self.layers.push(Layer {
-            weights: Matrix::uniform(neurons, prev_rows),
+        let init = init.into();
+        init.weights.check()?;
+        init.bias.check()?;
+        let weights = init.weights.init(neurons, input_size, &mut self.rng);
+        let bias = init.bias.init(neurons, 1, &mut self.rng);
+        self.push_layer(Layer {
+            weights,
+            bias,
             activation,
-            bias: Matrix::repeat(neurons, 1, 0.0),
-            d_activation,
         });
+        Ok(())
+    }
 
-        self.back_prop_states
-            .gradients
-            .push(Matrix::repeat(neurons, prev_rows, 0.0));
-
-        self.back_prop_states
-            .errors
-            .push(Matrix::repeat(neurons, 1, 0.0));
+    pub fn add_layer(
+        &mut self,
+        neurons: usize,
+        activation: Activation,
+        init: impl Into<Init>,
+    ) -> Result<()> {
+        let prev_rows = match self.layers.last() {
+            Some(layer) => layer.weights.rows,
+            None => {
+                return Err(Error::State(
+                    "Add an input layer before adding hidden layers".to_string(),
+                ))
+            }
+        };
+        self.add_inp_layer(neurons, prev_rows, activation, init)
     }
 
     pub fn depth(&self) -> usize {
         self.layers.len()
     }
 
-    pub fn feed_forward(&mut self, x: &Matrix, y: &Matrix) -> f64 {
-        let f_s = &mut self.feed_forward_states;
+    // Saves the architecture, activations, loss and parameters, as JSON for `.json` paths and in the
+    // compact binary format otherwise. Optimizer state is not part of the model.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        self.save_as(path, ModelFormat::from_path(path))
+    }
+
+    pub fn save_as(&self, path: impl AsRef<Path>, format: ModelFormat) -> Result<()> {
+        let loss = self
+            .loss
+            .to_saved()
+            .ok_or_else(|| Error::State("Loss function can't be saved".to_string()))?;
+        let model = SavedModel {
+            format_version: FORMAT_VERSION,
+            loss,
+            layers: self
+                .layers
+                .iter()
+                .map(|l| SavedLayer {
+                    weights: l.weights.clone(),
+                    bias: l.bias.clone(),
+                    activation: l.activation,
+                })
+                .collect(),
+            labels: self.labels.clone(),
+        };
+        model.write(path.as_ref(), format)
+    }
 
-        // Input layer
-        let input_layer = self.layers.first().unwrap();
-        let a = &input_layer.weights.dot(x).unwrap() + &input_layer.bias;
-        let z = (input_layer.activation)(&a);
-        f_s.pre_activation.push(a);
-        f_s.activations.push(z);
-
-        for i in 1..self.layers.len() {
-            let hidden_layer = &self.layers[i];
-            let a = &hidden_layer
-                .weights
-                .dot(f_s.activations.last().unwrap())
-                .unwrap()
-                + &hidden_layer.bias;
-            let z = (hidden_layer.activation)(&a);
+    pub(crate) fn training_state(&self, progress: Progress) -> Result<TrainingState> {
+        let optimizer = self
+            .optimizer
+            .to_saved()
+            .ok_or_else(|| Error::State("Optimizer can't be saved".to_string()))?;
+        Ok(TrainingState {
+            version: CHECKPOINT_VERSION,
+            progress,
+            optimizer,
+            gradients: self.back_prop_states.gradients.clone(),
+            errors: self.back_prop_states.errors.clone(),
+            samples: self.back_prop_states.samples,
+        })
+    }
+
+    pub(crate) fn restore_training_state(&mut self, state: TrainingState) -> Result<()> {
+        let shapes_match = |buffers: &[Matrix], shape: fn(&Layer) -> (usize, usize)| {
+            buffers.len() == self.layers.len()
+                && buffers
+                    .iter()
+                    .zip(self.layers.iter())
+                    .all(|(b, l)| (b.rows, b.cols) == shape(l))
+        };
+        if !shapes_match(&state.gradients, |l| (l.weights.rows, l.weights.cols))
+            || !shapes_match(&state.errors, |l| (l.bias.rows, l.bias.cols))
+        {
+            return Err(Error::Format(
+                "Gradient buffers do not match the network layers".to_string(),
+            ));
+        }
+        // Parameter 2 * i is the weights of layer i and 2 * i + 1 its bias, as in `step`
+        let shapes: Vec<(usize, usize)> = self
+            .layers
+            .iter()
+            .flat_map(|l| [(l.weights.rows, l.weights.cols), (l.bias.rows, l.bias.cols)])
+            .collect();
+        state.optimizer.check_shapes(&shapes)?;
+
+        self.optimizer = state.optimizer.restore();
+        self.back_prop_states.gradients = state.gradients;
+        self.back_prop_states.errors = state.errors;
+        self.back_prop_states.samples = state.samples;
+        Ok(())
+    }
+
+    // Loads a network saved in either format. It is ready for inference, to keep training it
+    // call `set_optimizer` as it starts with plain SGD at a learning rate of 0.01.
+    pub fn load(path: impl AsRef<Path>) -> Result<Network> {
+        let model = SavedModel::read(path.as_ref())?;
+        let mut nn = Network {
+            optimizer: Box::new(Sgd::new(0.01)),
+            loss: model.loss.restore(),
+            layers: vec![],
+            feed_forward_states: FeedForwardStates::new(),
+            back_prop_states: Gradients::new(),
+            rng: SeededRng::from_entropy(),
+            labels: model.labels,
+        };
+        for layer in model.layers {
+            nn.push_layer(Layer {
+                weights: layer.weights,
+                bias: layer.bias,
+                activation: layer.activation,
+            });
+        }
+        Ok(nn)
+    }
+
+    // Every sample must have as many features as the first layer has inputs
+    fn check_input(&self, x: &Matrix) -> Result<()> {
+        let inputs = match self.layers.first() {
+            Some(layer) => layer.weights.cols,
+            None => return Err(Error::State("The network has no layers".to_string())),
+        };
+        if x.rows != inputs {
+            return Err(Error::Shape(format!(
+                "Samples have {} features but the network takes {}",
+                x.rows, inputs
+            )));
+        }
+        Ok(())
+    }
+
+    // `x` holds one sample per column (features x batch_size) and `y` the matching labels.
+    // Returns the loss averaged over the batch. The intermediate states are kept for `calc_gradients`,
+    // replacing those of any previous call.
+    pub fn feed_forward(&mut self, x: &Matrix, y: &Matrix) -> Result<f64> {
+        self.check_input(x)?;
+        let f_s = &mut self.feed_forward_states;
+        f_s.pre_activation.clear();
+        f_s.activations.clear();
+
+        for layer in &self.layers {
+            // Bias is broadcast across every column of the batch
+            let input = f_s.activations.last().unwrap_or(x);
+            let a = &layer.weights.dot(input).unwrap() + &layer.bias;
+            let z = layer.activation.forward(&a);
             f_s.pre_activation.push(a);
             f_s.activations.push(z);
         }
 
-        (self.loss_fn)(f_s.activations.last().unwrap(), y)
+        let output = f_s.activations.last().unwrap();
+        if let Err(e) = self.loss.check_labels(output, y) {
+            f_s.pre_activation.clear();
+            f_s.activations.clear();
+            return Err(e);
+        }
+        Ok(self.loss.value(output, y))
+    }
+
+    // Inference only forward pass, no labels needed and no training state touched.
+    fn output(&self, x: &Matrix) -> Matrix {
+        self.layers.iter().fold(x.clone(), |input, layer| {
+            let a = &layer.weights.dot(&input).unwrap() + &layer.bias;
+            layer.activation.forward(&a)
+        })
     }
 
-    pub fn calc_gradients(&mut self, x: &mut Matrix, y: &Matrix) {
+    // Predicts every column of `x` (features x n). The network is only read, so a trained model can be
+    // shared across threads.
+    pub fn predict(&self, x: &Matrix) -> Result<Prediction> {
+        self.check_input(x)?;
+        Ok(self.to_prediction(self.output(x)))
+    }
+
+    // Loss averaged over the batch together with the predictions, without touching training state.
+    pub fn evaluate(&self, x: &Matrix, y: &Matrix) -> Result<(f64, Prediction)> {
+        self.check_input(x)?;
+        let output = self.output(x);
+        self.loss.check_labels(&output, y)?;
+        let loss = self.loss.value(&output, y);
+        Ok((loss, self.to_prediction(output)))
+    }
+
+    // Output of the last `feed_forward`, until `calc_gradients` consumes it
+    pub fn last_output(&self) -> Option<&Matrix> {
+        self.feed_forward_states.activations.last()
+    }
+
+    fn to_prediction(&self, output: Matrix) -> Prediction {
+        let scores = match self.layers.last().map(|l| l.activation) {
+            Some(Activation::LogSoftmax) => output.map(f64::exp),
+            Some(Activation::Identity) if self.loss.takes_logits() => softmax(&output),
+            _ => output,
+        };
+        Prediction { scores }
+    }
+
+    // Same as `predict` for separate (features x 1) samples, such as the ones from `idx_parser::parse`.
+    pub fn predict_batch(&self, samples: &[Matrix]) -> Result<Prediction> {
+        self.predict(&Matrix::from_columns(samples)?)
+    }
+
+    // Adds the gradients of the whole batch fed in the last `feed_forward` to the gradient buffers.
+    // Weight gradients are summed over the batch by the matrix products, bias gradients by `sum_cols`.
+    // The buffers keep growing until `step`, `update_gradients` or `zero_grad`, so calling this for
+    // several micro-batches before one update accumulates their gradients.
+    pub fn calc_gradients(&mut self, x: &mut Matrix, y: &Matrix) -> Result<()> {
         if !self.feed_forward_states.is_initialised() {
-            panic!("Feed forward state not initialised, feed training data first.");
+            return Err(Error::State(
+                "Feed forward state not initialised, feed training data first.".to_string(),
+            ));
         }
+        let output = self.feed_forward_states.activations.last().unwrap();
+        if x.rows != self.layers[0].weights.cols || x.cols != output.cols {
+            return Err(Error::Shape(
+                "`x` is not the batch of the last feed forward".to_string(),
+            ));
+        }
+        self.loss.check_labels(output, y)?;
 
         let f_s = &mut self.feed_forward_states;
-        //todo: Calculate error using derivative of output activation and loss function
-        let e = &f_s.activations.pop().unwrap() - y;
-        let _ = f_s.pre_activation.pop().unwrap(); // Last layer's pre-activation is not required
-        let mut z_prev = f_s.activations.pop().unwrap();
-        z_prev.transpose();
-        let grad = e.dot(&z_prev).unwrap();
+        let output = f_s.activations.pop().unwrap();
+        let a = f_s.pre_activation.pop().unwrap();
+        let mut e = match self.layers.last().unwrap().activation {
+            // Softmax output, use the loss' fused gradient when it has one
+            Activation::Softmax => self
+                .loss
+                .softmax_gradient(&output, y)
+                .unwrap_or_else(|| softmax_backward(&output, &self.loss.gradient(&output, y))),
+            // Chain rule: dL/da * activation'(a)
+            activation => activation.backward(&a, &self.loss.gradient(&output, y)),
+        };
 
         let g_s = &mut self.back_prop_states;
-        let mut errors: Vec<Matrix> = vec![];
-
-        errors.push(e);
-        let depth = g_s.gradients.len();
-        g_s.gradients[depth - 1] = g_s.gradients.last().unwrap() + &grad;
-
-        for i in (1..self.layers.len() - 1).rev() {
-            let d_activation = self.layers[i].d_activation;
-            let next_layer = &mut self.layers[i + 1];
-            next_layer.weights.transpose();
-
-            let e_next_layer = errors.last().unwrap();
-            let a = f_s.pre_activation.pop().unwrap();
-            let e = &next_layer.weights.dot(&e_next_layer).unwrap() * &(d_activation.unwrap())(&a);
-            let mut z_prev = f_s.activations.pop().unwrap();
-            z_prev.transpose();
-            let grad = e.dot(&z_prev).unwrap();
+        // Walk back from the output layer, `e` holds dL/da of layer `i`
+        for i in (0..self.layers.len()).rev() {
+            let grad = match f_s.activations.pop() {
+                Some(mut z_prev) => {
+                    z_prev.transpose();
+                    e.dot(&z_prev).unwrap()
+                }
+                // The first layer's input is the batch itself
+                None => {
+                    x.transpose();
+                    let grad = e.dot(x).unwrap();
+                    x.transpose();
+                    grad
+                }
+            };
             g_s.gradients[i] = &g_s.gradients[i] + &grad;
-            errors.push(e);
-
-            next_layer.weights.transpose();
+            g_s.errors[i] = &g_s.errors[i] + &e.sum_cols();
+
+            if i > 0 {
+                let activation = self.layers[i - 1].activation;
+                let weights = &mut self.layers[i].weights;
+                weights.transpose();
+                let a = f_s.pre_activation.pop().unwrap();
+                e = activation.backward(&a, &weights.dot(&e).unwrap());
+                weights.transpose();
+            }
         }
+        g_s.samples += x.cols;
+        Ok(())
+    }
 
-        let inp_activation = &mut self.layers[0].d_activation.unwrap();
-        let next_layer = &mut self.layers[1];
-        next_layer.weights.transpose();
-        let e_next_layer = errors.last().unwrap();
-        let a = f_s.pre_activation.pop().unwrap();
-        let e = &next_layer.weights.dot(&e_next_layer).unwrap() * &inp_activation(&a);
-        x.transpose();
-        let grad = e.dot(&x).unwrap();
-        x.transpose();
-        g_s.gradients[0] = &g_s.gradients[0] + &grad;
-        errors.push(e);
-        next_layer.weights.transpose();
+    // Number of samples whose gradients are waiting for the next update
+    pub fn accumulated_samples(&self) -> usize {
+        self.back_prop_states.samples
+    }
+
+    // Clears the gradient buffers without updating the parameters.
+    pub fn zero_grad(&mut self) {
+        self.back_prop_states.zero();
+    }
 
-        errors.reverse();
+    // One optimizer update with the gradients averaged over every sample accumulated since the
+    // buffers were last zeroed, then zeroes them. Does nothing when nothing was accumulated.
+    pub fn step(&mut self) {
+        let samples = self.back_prop_states.samples;
+        if samples > 0 {
+            self.apply_gradients(samples);
+        }
+    }
 
-        for i in 0..g_s.errors.len() {
-            g_s.errors[i] = &g_s.errors[i] + &errors[i];
+    // Same as `step` but averages over `batch_size` samples instead of the accumulated count.
+    pub fn update_gradients(&mut self, batch_size: usize) -> Result<()> {
+        if batch_size == 0 {
+            return Err(Error::InvalidArgument(
+                "Batch size must be at least 1".to_string(),
+            ));
         }
+        self.apply_gradients(batch_size);
+        Ok(())
     }
 
-    pub fn update_gradients(&mut self, batch_size: usize) {
+    // Weights and bias of layer `i` are the optimizer parameters `2 * i` and `2 * i + 1`.
+    fn apply_gradients(&mut self, batch_size: usize) {
         let g_s = &mut self.back_prop_states;
+        let scale = Matrix::new(1, 1, vec![1.0 / batch_size as f64]);
+
+        self.optimizer.begin_step();
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            self.optimizer
+                .update(2 * i, &mut layer.weights, &(&g_s.gradients[i] * &scale));
+            self.optimizer
+                .update(2 * i + 1, &mut layer.bias, &(&g_s.errors[i] * &scale));
+        }
+        g_s.zero();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::loss_functions::{
+        BinaryCrossEntropy, CrossEntropy, Hinge, SoftmaxCrossEntropy,
+    };
+    use crate::nn::initializer::{Init, Initializer};
+    use crate::nn::optimizer::Sgd;
+
+    fn network(loss: impl Loss + 'static, output: Activation) -> Network {
+        let mut nn = Network::new(Sgd::new(0.1), loss);
+        nn.set_seed(1);
+        nn.add_inp_layer(4, 3, Activation::Tanh, Initializer::XavierUniform)
+            .unwrap();
+        nn.add_layer(2, output, Initializer::XavierUniform).unwrap();
+        nn
+    }
+
+    #[test]
+    fn test_same_seed_same_network() {
+        let build = || {
+            let mut nn = Network::new(Sgd::new(0.1), CrossEntropy);
+            nn.set_seed(42);
+            nn.add_inp_layer(4, 3, Activation::Tanh, Initializer::XavierNormal)
+                .unwrap();
+            nn.add_layer(
+                2,
+                Activation::Softmax,
+                Initializer::Orthogonal { gain: 1.0 },
+            )
+            .unwrap();
+            nn
+        };
+        assert_eq!(build().seed(), 42);
+        assert_same_parameters(&build(), &build());
+    }
+
+    #[test]
+    fn test_layer_initialisers() {
+        let mut nn = Network::new(Sgd::new(0.1), CrossEntropy);
+        nn.add_inp_layer(4, 3, Activation::Relu, Initializer::Zeros)
+            .unwrap();
+        nn.add_layer(
+            2,
+            Activation::Softmax,
+            Init {
+                weights: Initializer::Constant(0.5),
+                bias: Initializer::Constant(0.1),
+            },
+        )
+        .unwrap();
+
+        assert!(nn.layers[0].weights.data().iter().all(|&w| w == 0.0));
+        assert!(nn.layers[0].bias.data().iter().all(|&b| b == 0.0));
+        assert!(nn.layers[1].weights.data().iter().all(|&w| w == 0.5));
+        assert!(nn.layers[1].bias.data().iter().all(|&b| b == 0.1));
+        assert_eq!(
+            (nn.layers[1].weights.rows, nn.layers[1].weights.cols),
+            (2, 4)
+        );
+    }
+
+    #[test]
+    fn test_bad_initialisers_are_rejected() {
+        let mut nn = Network::new(Sgd::new(0.1), CrossEntropy);
+        assert!(matches!(
+            nn.add_inp_layer(4, 3, Activation::Relu, Initializer::Uniform { limit: -0.5 }),
+            Err(Error::InvalidArgument(_))
+        ));
+        let nan_bias = Init {
+            weights: Initializer::Zeros,
+            bias: Initializer::Normal { std: f64::NAN },
+        };
+        assert!(matches!(
+            nn.add_inp_layer(4, 3, Activation::Relu, nan_bias),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert_eq!(nn.depth(), 0);
+    }
+
+    #[test]
+    fn test_misuse_is_an_error() {
+        let mut nn = Network::new(Sgd::new(0.1), CrossEntropy);
+        let x = Matrix::new(3, 1, vec![0.1, -0.3, 0.5]);
+        let y = Matrix::new(2, 1, vec![1.0, 0.0]);
+
+        assert!(matches!(
+            nn.add_layer(2, Activation::Softmax, Initializer::Zeros),
+            Err(Error::State(_))
+        ));
+        assert!(matches!(nn.predict(&x), Err(Error::State(_))));
+        assert!(matches!(
+            nn.set_labels(LabelEncoder::new(2).unwrap()),
+            Err(Error::State(_))
+        ));
+        assert!(matches!(
+            nn.add_inp_layer(0, 3, Activation::Tanh, Initializer::Zeros),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        let mut nn = network(CrossEntropy, Activation::Softmax);
+        assert!(matches!(
+            nn.calc_gradients(&mut x.clone(), &y),
+            Err(Error::State(_))
+        ));
+        assert!(matches!(
+            nn.update_gradients(0),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_wrong_sizes_are_errors() {
+        let mut nn = network(CrossEntropy, Activation::Softmax);
+        let x = Matrix::new(3, 2, vec![0.1, -0.3, 0.5, 0.2, -0.7, 0.9]);
+        let y = Matrix::new(2, 2, vec![1.0, 0.0, 0.0, 1.0]);
+        assert!(matches!(
+            nn.set_labels(LabelEncoder::new(3).unwrap()),
+            Err(Error::Shape(_))
+        ));
+        let wide = Matrix::new(4, 1, vec![0.0; 4]);
+
+        assert!(matches!(nn.predict(&wide), Err(Error::Shape(_))));
+        assert!(matches!(
+            nn.predict_batch(&[x.clone(), wide.clone()]),
+            Err(Error::Shape(_))
+        ));
+        assert!(matches!(nn.feed_forward(&wide, &y), Err(Error::Shape(_))));
+        // Bad labels leave nothing behind for `calc_gradients`
+        let three_classes = Matrix::new(3, 2, vec![1.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+        assert!(matches!(
+            nn.feed_forward(&x, &three_classes),
+            Err(Error::Shape(_))
+        ));
+        assert!(nn.last_output().is_none());
+
+        nn.feed_forward(&x, &y).unwrap();
+        let mut one_sample = Matrix::new(3, 1, vec![0.1, -0.3, 0.5]);
+        assert!(matches!(
+            nn.calc_gradients(&mut one_sample, &y),
+            Err(Error::Shape(_))
+        ));
+        assert!(matches!(
+            nn.evaluate(&x, &three_classes),
+            Err(Error::Shape(_))
+        ));
+    }
+
+    #[test]
+    fn test_predict_probabilities() {
+        let nn = network(CrossEntropy, Activation::Softmax);
+        let x = Matrix::new(3, 2, vec![0.1, -0.3, 0.5, 0.2, -0.7, 0.9]);
+
+        let prediction = nn.predict(&x).unwrap();
+
+        assert_eq!(prediction.scores.rows, 2);
+        assert_eq!(prediction.scores.cols, 2);
+        let totals = prediction.scores.sum_rows();
+        assert!(totals.data().iter().all(|t| (t - 1.0).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_predict_softmax_for_logits() {
+        let nn = network(SoftmaxCrossEntropy::new(), Activation::Identity);
+        let x = Matrix::new(3, 1, vec![0.1, -0.3, 0.5]);
+
+        let p = nn.predict(&x).unwrap().scores;
 
-        for i in 0..g_s.gradients.len() {
-            let layer = &mut self.layers[i];
-            layer.weights = &layer.weights
-                - &(&Matrix::repeat(
-                    layer.weights.rows,
-                    layer.weights.cols,
-                    self.learning_rate / batch_size as f64,
-                ) * &g_s.gradients[i]);
-
-            layer.bias = &layer.bias
-                - &(&Matrix::repeat(
-                    layer.bias.rows,
-                    layer.bias.cols,
-                    self.learning_rate / batch_size as f64,
-                ) * &g_s.errors[i]);
+        assert!((p.data().iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!(p.data().iter().all(|&p| p > 0.0));
+    }
+
+    #[test]
+    fn test_predict_raw_scores_without_probabilities() {
+        let nn = network(Hinge::default(), Activation::Identity);
+        let x = Matrix::new(3, 1, vec![0.1, -0.3, 0.5]);
+
+        let scores = nn.predict(&x).unwrap().scores;
+
+        assert_eq!(scores.data(), nn.output(&x).data());
+    }
+
+    #[test]
+    fn test_predict_batch_matches_predict() {
+        let nn = network(CrossEntropy, Activation::Softmax);
+        let a = Matrix::new(3, 1, vec![0.1, -0.3, 0.5]);
+        let b = Matrix::new(3, 1, vec![0.2, -0.7, 0.9]);
+
+        let batch = nn.predict_batch(&[a.clone(), b]).unwrap();
+        let single = nn.predict(&a).unwrap();
+
+        assert_eq!(batch.classes()[0], single.classes()[0]);
+        assert!((batch.scores.get(0, 0) - single.scores.get(0, 0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_top_k() {
+        let prediction = Prediction {
+            scores: Matrix::new(3, 2, vec![0.2, 0.1, 0.5, 0.3, 0.3, 0.6]),
+        };
+
+        assert_eq!(prediction.classes(), vec![1, 2]);
+        assert_eq!(
+            prediction.top_k(2),
+            vec![vec![(1, 0.5), (2, 0.3)], vec![(2, 0.6), (1, 0.3)]]
+        );
+    }
+
+    #[test]
+    fn test_predict_from_threads() {
+        let nn = network(CrossEntropy, Activation::Softmax);
+        let x = Matrix::new(3, 1, vec![0.1, -0.3, 0.5]);
+        let expected = nn.predict(&x).unwrap().scores;
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let p = nn.predict(&x).unwrap().scores;
+                    assert_eq!(p.data(), expected.data());
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_feed_forward_does_not_accumulate_states() {
+        let mut nn = network(CrossEntropy, Activation::Softmax);
+        let x = Matrix::new(3, 1, vec![0.1, -0.3, 0.5]);
+        let y = Matrix::new(2, 1, vec![1.0, 0.0]);
+
+        for _ in 0..3 {
+            nn.feed_forward(&x, &y).unwrap();
         }
+
+        assert_eq!(nn.feed_forward_states.activations.len(), nn.depth());
+    }
+
+    fn train_on(nn: &mut Network, x: &Matrix, y: &Matrix) {
+        let mut x = x.clone();
+        nn.feed_forward(&x, y).unwrap();
+        nn.calc_gradients(&mut x, y).unwrap();
+    }
+
+    #[test]
+    fn test_update_zeroes_gradients() {
+        let mut nn = network(CrossEntropy, Activation::Softmax);
+        let x = Matrix::new(3, 1, vec![0.1, -0.3, 0.5]);
+        let y = Matrix::new(2, 1, vec![1.0, 0.0]);
+
+        train_on(&mut nn, &x, &y);
+        assert_eq!(nn.accumulated_samples(), 1);
+        nn.step();
+
+        assert_eq!(nn.accumulated_samples(), 0);
+        let g_s = &nn.back_prop_states;
+        assert!(g_s
+            .gradients
+            .iter()
+            .all(|g| g.data().iter().all(|&v| v == 0.0)));
+        assert!(g_s
+            .errors
+            .iter()
+            .all(|e| e.data().iter().all(|&v| v == 0.0)));
+    }
+
+    #[test]
+    fn test_zero_grad_discards_gradients() {
+        let mut nn = network(CrossEntropy, Activation::Softmax);
+        let x = Matrix::new(3, 1, vec![0.1, -0.3, 0.5]);
+        let y = Matrix::new(2, 1, vec![1.0, 0.0]);
+        let before = nn.predict(&x).unwrap().scores;
+
+        train_on(&mut nn, &x, &y);
+        nn.zero_grad();
+        nn.step();
+
+        assert_eq!(nn.predict(&x).unwrap().scores.data(), before.data());
+    }
+
+    #[test]
+    fn test_micro_batches_accumulate_like_one_batch() {
+        let x = Matrix::new(3, 2, vec![0.1, -0.3, 0.5, 0.2, -0.7, 0.9]);
+        let y = Matrix::new(2, 2, vec![1.0, 0.0, 0.0, 1.0]);
+        let mut whole = network(CrossEntropy, Activation::Softmax);
+        let mut split = Network::new(Sgd::new(0.1), CrossEntropy);
+        for layer in &whole.layers {
+            split.push_layer(Layer {
+                weights: layer.weights.clone(),
+                bias: layer.bias.clone(),
+                activation: layer.activation,
+            });
+        }
+
+        train_on(&mut whole, &x, &y);
+        whole.step();
+        for j in 0..2 {
+            let column =
+                |m: &Matrix| Matrix::new(m.rows, 1, (0..m.rows).map(|i| m.get(i, j)).collect());
+            train_on(&mut split, &column(&x), &column(&y));
+        }
+        assert_eq!(split.accumulated_samples(), 2);
+        split.step();
+
+        for (a, b) in whole.layers.iter().zip(split.layers.iter()) {
+            for (p, q) in a.weights.data().iter().zip(b.weights.data()) {
+                assert!((p - q).abs() < 1e-12);
+            }
+            for (p, q) in a.bias.data().iter().zip(b.bias.data()) {
+                assert!((p - q).abs() < 1e-12);
+            }
+        }
+    }
+
+    fn parameter(nn: &mut Network, layer: usize, bias: bool) -> &mut Matrix {
+        let layer = &mut nn.layers[layer];
+        if bias {
+            &mut layer.bias
+        } else {
+            &mut layer.weights
+        }
+    }
+
+    fn nudge(m: &mut Matrix, r: usize, c: usize, delta: f64) {
+        let mut data = m.data().to_vec();
+        data[r * m.cols + c] += delta;
+        *m = Matrix::new(m.rows, m.cols, data);
+    }
+
+    // Compares the accumulated gradients with central differences of the loss summed over the batch
+    fn assert_gradients_match_numeric(mut nn: Network, x: &Matrix, y: &Matrix) {
+        train_on(&mut nn, x, y);
+        let eps = 1e-6;
+        for i in 0..nn.depth() {
+            for bias in [false, true] {
+                let (rows, cols) = {
+                    let m = parameter(&mut nn, i, bias);
+                    (m.rows, m.cols)
+                };
+                for r in 0..rows {
+                    for c in 0..cols {
+                        let mut loss_at = |delta: f64| {
+                            nudge(parameter(&mut nn, i, bias), r, c, delta);
+                            let loss = nn.evaluate(x, y).unwrap().0 * x.cols as f64;
+                            nudge(parameter(&mut nn, i, bias), r, c, -delta);
+                            loss
+                        };
+                        let numeric = (loss_at(eps) - loss_at(-eps)) / (2.0 * eps);
+                        let g_s = &nn.back_prop_states;
+                        let buffer = if bias {
+                            &g_s.errors[i]
+                        } else {
+                            &g_s.gradients[i]
+                        };
+                        assert!((numeric - buffer.get(r, c)).abs() < 1e-5);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_gradients_for_any_depth() {
+        let x = Matrix::new(3, 2, vec![0.1, -0.3, 0.5, 0.2, -0.7, 0.9]);
+        let y = Matrix::new(2, 2, vec![1.0, 0.0, 0.0, 1.0]);
+        for hidden in [vec![], vec![4], vec![4, 3], vec![5, 4, 3]] {
+            let mut nn = Network::new(Sgd::new(0.1), CrossEntropy);
+            let mut inputs = 3;
+            for &neurons in &hidden {
+                if nn.depth() == 0 {
+                    nn.add_inp_layer(
+                        neurons,
+                        inputs,
+                        Activation::Tanh,
+                        Initializer::XavierUniform,
+                    )
+                    .unwrap();
+                } else {
+                    nn.add_layer(neurons, Activation::Tanh, Initializer::XavierUniform)
+                        .unwrap();
+                }
+                inputs = neurons;
+            }
+            if nn.depth() == 0 {
+                nn.add_inp_layer(2, inputs, Activation::Softmax, Initializer::XavierUniform)
+                    .unwrap();
+            } else {
+                nn.add_layer(2, Activation::Softmax, Initializer::XavierUniform)
+                    .unwrap();
+            }
+            assert_eq!(nn.depth(), hidden.len() + 1);
+            assert_gradients_match_numeric(nn, &x, &y);
+        }
+    }
+
+    #[test]
+    fn test_single_layer_logistic_regression() {
+        let mut nn = Network::new(Sgd::new(0.5), BinaryCrossEntropy);
+        nn.add_inp_layer(1, 2, Activation::Sigmoid, Initializer::XavierUniform)
+            .unwrap();
+        let x = Matrix::new(2, 4, vec![1.0, -1.0, 0.5, -0.5, 0.5, -0.5, 1.0, -1.0]);
+        let y = Matrix::new(1, 4, vec![1.0, 0.0, 1.0, 0.0]);
+
+        let before = nn.evaluate(&x, &y).unwrap().0;
+        for _ in 0..50 {
+            train_on(&mut nn, &x, &y);
+            nn.step();
+        }
+
+        assert!(nn.evaluate(&x, &y).unwrap().0 < before);
+    }
+
+    fn assert_same_parameters(a: &Network, b: &Network) {
+        assert_eq!(a.depth(), b.depth());
+        for (la, lb) in a.layers.iter().zip(b.layers.iter()) {
+            assert_eq!(la.weights.data(), lb.weights.data());
+            assert_eq!(la.bias.data(), lb.bias.data());
+            assert_eq!(la.activation, lb.activation);
+        }
+    }
+
+    #[test]
+    fn test_save_load_round_trip() {
+        let mut nn = Network::new(
+            Sgd::new(0.1),
+            SoftmaxCrossEntropy::new()
+                .with_label_smoothing(0.1)
+                .unwrap(),
+        );
+        nn.add_inp_layer(
+            4,
+            3,
+            Activation::LeakyRelu { alpha: 0.2 },
+            Initializer::HeUniform,
+        )
+        .unwrap();
+        nn.add_layer(2, Activation::Identity, Initializer::XavierUniform)
+            .unwrap();
+        let names = vec!["cat".to_string(), "dog".to_string()];
+        nn.set_labels(LabelEncoder::with_names(names).unwrap())
+            .unwrap();
+        let x = Matrix::new(3, 2, vec![0.1, -0.3, 0.5, 0.2, -0.7, 0.9]);
+
+        let dir = std::env::temp_dir();
+        for file in ["nn_scratch_round_trip.json", "nn_scratch_round_trip.bin"] {
+            let path = dir.join(file);
+            nn.save(&path).unwrap();
+            let loaded = Network::load(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_same_parameters(&nn, &loaded);
+            assert_eq!(loaded.loss.to_saved(), nn.loss.to_saved());
+            assert_eq!(loaded.labels(), nn.labels());
+            assert_eq!(
+                loaded.predict(&x).unwrap().scores.data(),
+                nn.predict(&x).unwrap().scores.data()
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_rejects_corrupted_strides() {
+        let nn = network(CrossEntropy, Activation::Softmax);
+        let path = std::env::temp_dir().join("nn_scratch_strides.json");
+        nn.save(&path).unwrap();
+        let mut json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        json["layers"][0]["weights"]["row_stride"] = 1000.into();
+        std::fs::write(&path, json.to_string()).unwrap();
+
+        let err = Network::load(&path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(err, Error::Format(_)));
+    }
+
+    #[test]
+    fn test_load_rejects_truncated_binary() {
+        let nn = network(CrossEntropy, Activation::Softmax);
+        let path = std::env::temp_dir().join("nn_scratch_truncated.bin");
+        nn.save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+
+        let err = Network::load(&path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(err, Error::Format(_)));
     }
 }
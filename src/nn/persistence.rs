@@ -0,0 +1,217 @@
+use crate::data::labels::LabelEncoder;
+use crate::error::{Error, Result};
+use crate::math::activation::Activation;
+use crate::math::loss_functions::SavedLoss;
+use crate::math::matrix::Matrix;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// Bumped whenever the saved layout changes, files from newer versions are rejected on load.
+pub const FORMAT_VERSION: u32 = 1;
+const BINARY_MAGIC: &[u8; 4] = b"NNSC";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModelFormat {
+    // Human readable, weights are written as JSON numbers
+    Json,
+    // JSON header describing the architecture followed by the raw little-endian f64 parameters
+    Binary,
+}
+
+impl ModelFormat {
+    // `.json` files are saved as JSON, anything else in the binary format
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("json") => ModelFormat::Json,
+            _ => ModelFormat::Binary,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SavedLayer {
+    pub weights: Matrix,
+    pub bias: Matrix,
+    pub activation: Activation,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SavedModel {
+    pub format_version: u32,
+    pub loss: SavedLoss,
+    pub layers: Vec<SavedLayer>,
+    // Class names of the outputs, when the network was given them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<LabelEncoder>,
+}
+
+// Binary header, parameters follow it in layer order: weights row by row, then bias.
+#[derive(Serialize, Deserialize)]
+struct BinaryHeader {
+    format_version: u32,
+    loss: SavedLoss,
+    layers: Vec<BinaryLayer>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    labels: Option<LabelEncoder>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BinaryLayer {
+    neurons: usize,
+    inputs: usize,
+    activation: Activation,
+}
+
+impl SavedModel {
+    pub fn write(&self, path: &Path, format: ModelFormat) -> Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        match format {
+            ModelFormat::Json => serde_json::to_writer(&mut w, self)?,
+            ModelFormat::Binary => self.write_binary(&mut w)?,
+        }
+        Ok(w.flush()?)
+    }
+
+    // The format is detected from the file contents, not its extension.
+    pub fn read(path: &Path) -> Result<SavedModel> {
+        let mut bytes = vec![];
+        BufReader::new(File::open(path)?).read_to_end(&mut bytes)?;
+
+        let model = if bytes.starts_with(BINARY_MAGIC) {
+            SavedModel::read_binary(&bytes[BINARY_MAGIC.len()..])?
+        } else {
+            serde_json::from_slice::<SavedModel>(&bytes)?
+        };
+
+        if model.format_version > FORMAT_VERSION {
+            return Err(Error::Format(format!(
+                "Model format version {} is newer than the supported version {}",
+                model.format_version, FORMAT_VERSION
+            )));
+        }
+        model.check_shapes()?;
+        Ok(model)
+    }
+
+    fn write_binary(&self, w: &mut impl Write) -> Result<()> {
+        let header = BinaryHeader {
+            format_version: self.format_version,
+            loss: self.loss.clone(),
+            layers: self
+                .layers
+                .iter()
+                .map(|l| BinaryLayer {
+                    neurons: l.weights.rows,
+                    inputs: l.weights.cols,
+                    activation: l.activation,
+                })
+                .collect(),
+            labels: self.labels.clone(),
+        };
+        let header = serde_json::to_vec(&header)?;
+
+        w.write_all(BINARY_MAGIC)?;
+        w.write_all(&(header.len() as u64).to_le_bytes())?;
+        w.write_all(&header)?;
+        for layer in &self.layers {
+            write_matrix(w, &layer.weights)?;
+            write_matrix(w, &layer.bias)?;
+        }
+        Ok(())
+    }
+
+    fn read_binary(mut bytes: &[u8]) -> Result<SavedModel> {
+        let header_len = u64::from_le_bytes(take(&mut bytes, 8)?.try_into().unwrap()) as usize;
+        let header: BinaryHeader = serde_json::from_slice(take(&mut bytes, header_len)?)?;
+
+        let mut layers = Vec::with_capacity(header.layers.len());
+        for layer in header.layers {
+            layers.push(SavedLayer {
+                weights: read_matrix(&mut bytes, layer.neurons, layer.inputs)?,
+                bias: read_matrix(&mut bytes, layer.neurons, 1)?,
+                activation: layer.activation,
+            });
+        }
+        if !bytes.is_empty() {
+            return Err(Error::Format(
+                "Trailing data after the model parameters".to_string(),
+            ));
+        }
+
+        Ok(SavedModel {
+            format_version: header.format_version,
+            loss: header.loss,
+            layers,
+            labels: header.labels,
+        })
+    }
+
+    // Every layer must take the previous layer's output and have a (neurons x 1) bias
+    fn check_shapes(&self) -> Result<()> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            let (w, b) = (&layer.weights, &layer.bias);
+            if w.data().len() != w.rows * w.cols || b.data().len() != b.rows * b.cols {
+                return Err(Error::Format(format!(
+                    "Layer {} data does not match its shape",
+                    i
+                )));
+            }
+            if b.rows != w.rows || b.cols != 1 {
+                return Err(Error::Format(format!(
+                    "Layer {} bias does not match its weights",
+                    i
+                )));
+            }
+            if i > 0 && w.cols != self.layers[i - 1].weights.rows {
+                return Err(Error::Format(format!(
+                    "Layer {} input size does not match layer {}",
+                    i,
+                    i - 1
+                )));
+            }
+        }
+        if let (Some(labels), Some(last)) = (&self.labels, self.layers.last()) {
+            if labels.classes() != last.weights.rows {
+                return Err(Error::Format(format!(
+                    "{} class names for {} outputs",
+                    labels.classes(),
+                    last.weights.rows
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_matrix(w: &mut impl Write, m: &Matrix) -> Result<()> {
+    for i in 0..m.rows {
+        for j in 0..m.cols {
+            w.write_all(&m.get(i, j).to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_matrix(bytes: &mut &[u8], rows: usize, cols: usize) -> Result<Matrix> {
+    let len = rows
+        .checked_mul(cols)
+        .and_then(|n| n.checked_mul(8))
+        .ok_or_else(|| Error::Format("Layer shape is too large".to_string()))?;
+    let data = take(bytes, len)?
+        .chunks_exact(8)
+        .map(|c| f64::from_le_bytes(c.try_into().unwrap()))
+        .collect();
+    Ok(Matrix::new(rows, cols, data))
+}
+
+// Splits `n` bytes off the front of `bytes`
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if bytes.len() < n {
+        return Err(Error::Format("Model file is truncated".to_string()));
+    }
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    Ok(head)
+}
@@ -0,0 +1,478 @@
+use crate::data::dataset::{self, Dataset};
+use crate::data::loader::DataLoader;
+use crate::error::{Error, Result};
+use crate::math::matrix::Matrix;
+use crate::nn::checkpoint::{self, Progress};
+use crate::nn::perceptron::Network;
+use std::path::PathBuf;
+
+pub struct TrainConfig {
+    pub epochs: usize,
+    pub batch_size: usize,
+    // Mini-batches whose gradients are accumulated into each optimizer update
+    pub accumulation_steps: usize,
+    // Reshuffle the training samples at the start of every epoch
+    pub shuffle: bool,
+    // Fraction of the samples held out for validation, taken from the end unless `stratify` is set
+    pub validation_split: f64,
+    // Hold out a seeded random `validation_split` of every class instead of the tail
+    pub stratify: bool,
+    pub seed: u64,
+    // A checkpoint is written here after every epoch when set
+    pub checkpoint_dir: Option<PathBuf>,
+    // Also checkpoint every this many batches within an epoch
+    pub checkpoint_every: Option<usize>,
+    // Print the metrics of every epoch
+    pub verbose: bool,
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        TrainConfig {
+            epochs: 10,
+            batch_size: 32,
+            accumulation_steps: 1,
+            shuffle: true,
+            validation_split: 0.0,
+            stratify: false,
+            seed: 0,
+            checkpoint_dir: None,
+            checkpoint_every: None,
+            verbose: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpochMetrics {
+    pub epoch: usize,
+    pub train_loss: f64,
+    pub train_accuracy: f64,
+    pub validation_loss: Option<f64>,
+    pub validation_accuracy: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    pub epochs: Vec<EpochMetrics>,
+}
+
+pub struct Trainer {
+    config: TrainConfig,
+}
+
+impl Trainer {
+    pub fn new(config: TrainConfig) -> Result<Self> {
+        let invalid = |msg: &str| Err(Error::InvalidArgument(msg.to_string()));
+        if config.batch_size == 0 {
+            return invalid("Batch size must be at least 1");
+        }
+        if config.accumulation_steps == 0 {
+            return invalid("Accumulation steps must be at least 1");
+        }
+        if !(0.0..1.0).contains(&config.validation_split) {
+            return invalid("Validation split must be within [0, 1)");
+        }
+        Ok(Trainer { config })
+    }
+
+    pub fn fit<D: Dataset + ?Sized>(&self, network: &mut Network, samples: &D) -> Result<History> {
+        let progress = Progress {
+            seed: self.config.seed,
+            ..Progress::default()
+        };
+        self.fit_from(network, samples, progress)
+    }
+
+    // Picks up a run from its checkpoint, `samples` and the config must be the ones it was started with.
+    pub fn resume_from<D: Dataset + ?Sized>(&self, samples: &D) -> Result<(Network, History)> {
+        let dir = self
+            .config
+            .checkpoint_dir
+            .as_ref()
+            .ok_or_else(|| Error::State("No checkpoint directory configured".to_string()))?;
+        let (mut network, progress) = checkpoint::resume_from(dir)?;
+        let history = self.fit_from(&mut network, samples, progress)?;
+        Ok((network, history))
+    }
+
+    fn fit_from<D: Dataset + ?Sized>(
+        &self,
+        network: &mut Network,
+        samples: &D,
+        mut progress: Progress,
+    ) -> Result<History> {
+        let (train, validation) = match self.config.stratify {
+            true => dataset::stratified_split(samples, self.config.validation_split, progress.seed),
+            false => dataset::tail_split(samples, self.config.validation_split),
+        };
+        // Training batches are augmented if the dataset is set up for it, validation ones never
+        let mut loader = DataLoader::new(&train, self.config.batch_size)?.augment(progress.seed);
+        if self.config.shuffle {
+            loader = loader.shuffle(progress.seed);
+        }
+        let mut history = History::default();
+
+        while progress.epoch < self.config.epochs {
+            let batches = loader.epoch(progress.epoch);
+            for (b, batch) in batches.enumerate().skip(progress.batch) {
+                let (mut x, y) = batch?;
+                progress.loss += network.feed_forward(&x, &y)? * x.cols as f64;
+                progress.correct += correct_predictions(network.last_output().unwrap(), &y);
+                progress.seen += x.cols;
+                network.calc_gradients(&mut x, &y)?;
+
+                progress.batch = b + 1;
+                let mid_epoch = progress.batch < loader.len();
+                // The last batches of an epoch are applied even when fewer than `accumulation_steps`
+                if !mid_epoch
+                    || progress
+                        .batch
+                        .is_multiple_of(self.config.accumulation_steps)
+                {
+                    network.step();
+                }
+                let due = self
+                    .config
+                    .checkpoint_every
+                    .is_some_and(|every| progress.batch.is_multiple_of(every));
+                if mid_epoch && due {
+                    self.checkpoint(network, progress)?;
+                }
+            }
+
+            let (validation_loss, validation_accuracy) = if validation.is_empty() {
+                (None, None)
+            } else {
+                let (loss, accuracy) = self.validate(network, &validation)?;
+                (Some(loss), Some(accuracy))
+            };
+            let seen = progress.seen.max(1) as f64;
+            let metrics = EpochMetrics {
+                epoch: progress.epoch,
+                train_loss: progress.loss / seen,
+                train_accuracy: progress.correct as f64 / seen,
+                validation_loss,
+                validation_accuracy,
+            };
+            if self.config.verbose {
+                println!(
+                    "Epoch: {} Train Loss: {:.5} Train Accuracy: {:.4} Validation Loss: {:.5} Validation Accuracy: {:.4}",
+                    metrics.epoch,
+                    metrics.train_loss,
+                    metrics.train_accuracy,
+                    metrics.validation_loss.unwrap_or(f64::NAN),
+                    metrics.validation_accuracy.unwrap_or(f64::NAN),
+                );
+            }
+            history.epochs.push(metrics);
+
+            progress = Progress {
+                epoch: progress.epoch + 1,
+                seed: progress.seed,
+                ..Progress::default()
+            };
+            self.checkpoint(network, progress)?;
+        }
+
+        Ok(history)
+    }
+
+    fn validate(&self, network: &Network, validation: &impl Dataset) -> Result<(f64, f64)> {
+        let (mut loss, mut correct) = (0.0, 0);
+        for batch in DataLoader::new(validation, self.config.batch_size)?.epoch(0) {
+            let (x, y) = batch?;
+            let (batch_loss, prediction) = network.evaluate(&x, &y)?;
+            loss += batch_loss * x.cols as f64;
+            correct += correct_predictions(&prediction.scores, &y);
+        }
+        let n = validation.len() as f64;
+        Ok((loss / n, correct as f64 / n))
+    }
+
+    fn checkpoint(&self, network: &Network, progress: Progress) -> Result<()> {
+        match &self.config.checkpoint_dir {
+            Some(dir) => checkpoint::save(dir, network, progress),
+            None => Ok(()),
+        }
+    }
+}
+
+// Number of columns whose most likely class matches the label. Labels are one-hot columns or,
+// for `Nll`, a single row of class indices. A single output is a binary prediction thresholded at 0.5.
+fn correct_predictions(output: &Matrix, y: &Matrix) -> usize {
+    (0..y.cols)
+        .filter(|&j| match (output.rows, y.rows) {
+            (1, _) => (output.get(0, j) >= 0.5) == (y.get(0, j) >= 0.5),
+            (_, 1) => output.argmax_col(j) == y.get(0, j) as usize,
+            _ => output.argmax_col(j) == y.argmax_col(j),
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::dataset::Samples;
+    use crate::math::activation::Activation;
+    use crate::math::loss_functions::SoftmaxCrossEntropy;
+    use crate::nn::initializer::Initializer;
+    use crate::nn::optimizer::Adam;
+
+    // Two classes split by the sign of x0 + x1
+    fn toy_samples(n: usize) -> (Vec<Matrix>, Vec<Matrix>) {
+        let mut inputs = vec![];
+        let mut labels = vec![];
+        for i in 0..n {
+            let a = ((i * 37) % 17) as f64 / 8.0 - 1.0;
+            let b = ((i * 11) % 13) as f64 / 6.0 - 1.0;
+            inputs.push(Matrix::new(2, 1, vec![a, b]));
+            let class = if a + b > 0.0 { 1.0 } else { 0.0 };
+            labels.push(Matrix::new(2, 1, vec![1.0 - class, class]));
+        }
+        (inputs, labels)
+    }
+
+    fn toy_network() -> Network {
+        let mut nn = Network::new(Adam::new(0.05), SoftmaxCrossEntropy::new());
+        nn.set_seed(1);
+        nn.add_inp_layer(8, 2, Activation::Tanh, Initializer::XavierUniform)
+            .unwrap();
+        nn.add_layer(2, Activation::Identity, Initializer::XavierUniform)
+            .unwrap();
+        nn
+    }
+
+    #[test]
+    fn test_fit_records_history() {
+        let (inputs, labels) = toy_samples(200);
+        let mut nn = toy_network();
+        let trainer = Trainer::new(TrainConfig {
+            epochs: 3,
+            batch_size: 16,
+            validation_split: 0.2,
+            ..TrainConfig::default()
+        })
+        .unwrap();
+
+        let history = trainer
+            .fit(&mut nn, &Samples::new(&inputs, &labels).unwrap())
+            .unwrap();
+
+        assert_eq!(history.epochs.len(), 3);
+        for (e, metrics) in history.epochs.iter().enumerate() {
+            assert_eq!(metrics.epoch, e);
+            assert!(metrics.train_loss.is_finite());
+            assert!((0.0..=1.0).contains(&metrics.train_accuracy));
+            assert!(metrics.validation_loss.is_some());
+            assert!(metrics.validation_accuracy.is_some());
+        }
+    }
+
+    #[test]
+    fn test_fit_learns() {
+        let (inputs, labels) = toy_samples(200);
+        for accumulation_steps in [1, 3] {
+            let mut nn = toy_network();
+            let trainer = Trainer::new(TrainConfig {
+                epochs: 20,
+                batch_size: 16,
+                accumulation_steps,
+                validation_split: 0.2,
+                ..TrainConfig::default()
+            })
+            .unwrap();
+
+            let history = trainer
+                .fit(&mut nn, &Samples::new(&inputs, &labels).unwrap())
+                .unwrap();
+
+            let first = &history.epochs[0];
+            let last = history.epochs.last().unwrap();
+            assert!(last.train_loss < first.train_loss);
+            assert!(last.validation_accuracy.unwrap() > 0.9);
+            assert_eq!(nn.accumulated_samples(), 0);
+        }
+    }
+
+    #[test]
+    fn test_same_seed_is_bit_identical() {
+        let (inputs, labels) = toy_samples(60);
+        let run = || {
+            let mut nn = toy_network();
+            let trainer = Trainer::new(TrainConfig {
+                epochs: 3,
+                batch_size: 8,
+                seed: 9,
+                ..TrainConfig::default()
+            })
+            .unwrap();
+            let history = trainer
+                .fit(&mut nn, &Samples::new(&inputs, &labels).unwrap())
+                .unwrap();
+            (history.epochs, nn.predict(&inputs[0]).unwrap().scores)
+        };
+
+        let (history, p) = run();
+        let (again, q) = run();
+        assert_eq!(history, again);
+        assert_eq!(p.data(), q.data());
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let (inputs, labels) = toy_samples(40);
+        let dir = std::env::temp_dir().join("nn_scratch_trainer_resume");
+        let config = |epochs| TrainConfig {
+            epochs,
+            batch_size: 8,
+            checkpoint_dir: Some(dir.clone()),
+            ..TrainConfig::default()
+        };
+
+        let mut nn = toy_network();
+        Trainer::new(config(2))
+            .unwrap()
+            .fit(&mut nn, &Samples::new(&inputs, &labels).unwrap())
+            .unwrap();
+        let (_, history) = Trainer::new(config(5))
+            .unwrap()
+            .resume_from(&Samples::new(&inputs, &labels).unwrap())
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let epochs: Vec<usize> = history.epochs.iter().map(|m| m.epoch).collect();
+        assert_eq!(epochs, vec![2, 3, 4]);
+    }
+
+    // Fails the `fail_at`th batch it is asked for, like a run killed part way through an epoch
+    struct Interrupted<'a> {
+        samples: Samples<'a>,
+        fail_at: usize,
+        batches: std::cell::Cell<usize>,
+    }
+
+    impl Dataset for Interrupted<'_> {
+        fn len(&self) -> usize {
+            self.samples.len()
+        }
+
+        fn get(&self, index: usize) -> (Matrix, Matrix) {
+            self.samples.get(index)
+        }
+
+        fn batch(&self, indices: &[usize]) -> Result<(Matrix, Matrix)> {
+            self.batches.set(self.batches.get() + 1);
+            match self.batches.get() == self.fail_at {
+                true => Err(Error::State("Interrupted".to_string())),
+                false => self.samples.batch(indices),
+            }
+        }
+    }
+
+    #[test]
+    fn test_resume_mid_epoch_reports_the_whole_epoch() {
+        let (inputs, labels) = toy_samples(40);
+        let samples = Samples::new(&inputs, &labels).unwrap();
+        let dir = std::env::temp_dir().join("nn_scratch_trainer_resume_mid_epoch");
+        let config = || TrainConfig {
+            epochs: 1,
+            batch_size: 8,
+            checkpoint_dir: Some(dir.clone()),
+            checkpoint_every: Some(2),
+            ..TrainConfig::default()
+        };
+
+        let interrupted = Interrupted {
+            samples,
+            fail_at: 4,
+            batches: std::cell::Cell::new(0),
+        };
+        let trainer = Trainer::new(config()).unwrap();
+        assert!(trainer.fit(&mut toy_network(), &interrupted).is_err());
+        let (_, resumed) = trainer.resume_from(&samples).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let whole = Trainer::new(TrainConfig {
+            checkpoint_dir: None,
+            ..config()
+        })
+        .unwrap()
+        .fit(&mut toy_network(), &samples)
+        .unwrap();
+
+        let (resumed, whole) = (&resumed.epochs[0], &whole.epochs[0]);
+        assert!((resumed.train_loss - whole.train_loss).abs() < 1e-12);
+        assert_eq!(resumed.train_accuracy, whole.train_accuracy);
+    }
+
+    #[test]
+    fn test_resumed_run_matches_an_uninterrupted_one() {
+        let (inputs, labels) = toy_samples(40);
+        let samples = Samples::new(&inputs, &labels).unwrap();
+        let dir = std::env::temp_dir().join("nn_scratch_trainer_resume_identical");
+        let config = |checkpoint_dir| TrainConfig {
+            epochs: 2,
+            batch_size: 8,
+            seed: 6,
+            checkpoint_dir,
+            checkpoint_every: Some(2),
+            ..TrainConfig::default()
+        };
+
+        // Stops in the second epoch, resuming after its second batch
+        let interrupted = Interrupted {
+            samples,
+            fail_at: 8,
+            batches: std::cell::Cell::new(0),
+        };
+        let trainer = Trainer::new(config(Some(dir.clone()))).unwrap();
+        assert!(trainer.fit(&mut toy_network(), &interrupted).is_err());
+        let (resumed, _) = trainer.resume_from(&samples).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut whole = toy_network();
+        Trainer::new(config(None))
+            .unwrap()
+            .fit(&mut whole, &samples)
+            .unwrap();
+
+        // Same batches in the same order with the same optimizer steps give the same parameters
+        let optimizer = |nn: &Network| {
+            let state = nn.training_state(Progress::default()).unwrap();
+            serde_json::to_string(&state.optimizer).unwrap()
+        };
+        assert_eq!(optimizer(&resumed), optimizer(&whole));
+        assert_eq!(
+            resumed.predict(&inputs[0]).unwrap().scores.data(),
+            whole.predict(&inputs[0]).unwrap().scores.data()
+        );
+    }
+
+    #[test]
+    fn test_stratified_validation() {
+        let (inputs, labels) = toy_samples(200);
+        let mut nn = toy_network();
+        let trainer = Trainer::new(TrainConfig {
+            epochs: 5,
+            batch_size: 16,
+            validation_split: 0.2,
+            stratify: true,
+            seed: 4,
+            ..TrainConfig::default()
+        })
+        .unwrap();
+
+        let history = trainer
+            .fit(&mut nn, &Samples::new(&inputs, &labels).unwrap())
+            .unwrap();
+        assert!(history.epochs.iter().all(|m| m.validation_loss.is_some()));
+    }
+
+    #[test]
+    fn test_correct_predictions() {
+        let output = Matrix::new(2, 3, vec![0.9, 0.2, 0.4, 0.1, 0.8, 0.6]);
+        let one_hot = Matrix::new(2, 3, vec![1.0, 0.0, 1.0, 0.0, 1.0, 0.0]);
+        let indices = Matrix::new(1, 3, vec![0.0, 1.0, 0.0]);
+        assert_eq!(correct_predictions(&output, &one_hot), 2);
+        assert_eq!(correct_predictions(&output, &indices), 2);
+    }
+}